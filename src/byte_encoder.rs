@@ -1,5 +1,9 @@
 use std::io::{self, Read, Write};
 
+const SIGN_BIT: u64 = 1 << 63;
+const ESCAPED_ZERO: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
 pub struct ByteEncoder<T: Write> {
     pub inner: T,
 }
@@ -46,6 +50,47 @@ impl<T: Read> ByteDecoder<T> {
         let value = u128::from_le_bytes(buf);
         Ok(value)
     }
+
+    pub fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn read_ordered_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    pub fn read_ordered_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    pub fn read_ordered_i64(&mut self) -> io::Result<i64> {
+        Ok((self.read_ordered_u64()? ^ SIGN_BIT) as i64)
+    }
+
+    pub fn read_ordered_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let mut value = vec![];
+        loop {
+            match self.read_u8()? {
+                0 => match self.read_u8()? {
+                    ESCAPED_ZERO => value.push(0),
+                    TERMINATOR => return Ok(value),
+                    byte => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("bad escape 0x00 {byte:#04x} in ordered bytes"),
+                        ))
+                    }
+                },
+                byte => value.push(byte),
+            }
+        }
+    }
 }
 
 impl<T: Write> ByteEncoder<T> {
@@ -82,4 +127,87 @@ impl<T: Write> ByteEncoder<T> {
         self.inner.write_all(&buf)?;
         Ok(())
     }
+
+    pub fn write_bytes(&mut self, value: &[u8]) -> io::Result<()> {
+        self.inner.write_all(value)?;
+        Ok(())
+    }
+
+    /// The `write_ordered_*` methods write memcomparable encodings: comparing the
+    /// output bytewise orders the same as comparing the values, so concatenations of
+    /// them make composite keys. Integers are big-endian, signed ones with the sign bit
+    /// flipped.
+    pub fn write_ordered_u32(&mut self, value: u32) -> io::Result<()> {
+        self.inner.write_all(&value.to_be_bytes())
+    }
+
+    pub fn write_ordered_u64(&mut self, value: u64) -> io::Result<()> {
+        self.inner.write_all(&value.to_be_bytes())
+    }
+
+    pub fn write_ordered_i64(&mut self, value: i64) -> io::Result<()> {
+        self.write_ordered_u64(value as u64 ^ SIGN_BIT)
+    }
+
+    /// Zero bytes are escaped as `00 ff` and the value ends with `00 01`, so a value
+    /// sorts before every longer value it is a prefix of and fields after it still
+    /// compare.
+    pub fn write_ordered_bytes(&mut self, value: &[u8]) -> io::Result<()> {
+        for chunk in value.split_inclusive(|byte| *byte == 0) {
+            self.inner.write_all(chunk)?;
+            if chunk.last() == Some(&0) {
+                self.inner.write_all(&[ESCAPED_ZERO])?;
+            }
+        }
+        self.inner.write_all(&[0, TERMINATOR])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ordered<F: Fn(&mut ByteEncoder<Vec<u8>>) -> io::Result<()>>(write: F) -> Vec<u8> {
+        let mut encoder = ByteEncoder::new(vec![]);
+        write(&mut encoder).unwrap();
+        encoder.inner
+    }
+
+    #[test]
+    fn ordered_integers_sort_like_values() {
+        let values = [i64::MIN, -1_000_000, -1, 0, 1, 1_000_000, i64::MAX];
+        let encoded: Vec<_> = values
+            .iter()
+            .map(|value| ordered(|encoder| encoder.write_ordered_i64(*value)))
+            .collect();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+        for (value, bytes) in values.iter().zip(&encoded) {
+            let decoded = ByteDecoder::new(&bytes[..]).read_ordered_i64().unwrap();
+            assert_eq!(decoded, *value);
+        }
+
+        let low = ordered(|encoder| encoder.write_ordered_u32(255));
+        let high = ordered(|encoder| encoder.write_ordered_u32(256));
+        assert!(low < high);
+    }
+
+    #[test]
+    fn ordered_bytes_sort_like_values_and_compose() {
+        let values: [&[u8]; 5] = [b"", b"\0", b"\0\0", b"a", b"a\0b"];
+        let encoded: Vec<_> = values
+            .iter()
+            .map(|value| {
+                ordered(|encoder| {
+                    encoder.write_ordered_bytes(value)?;
+                    encoder.write_ordered_u32(7)
+                })
+            })
+            .collect();
+        assert!(encoded.windows(2).all(|pair| pair[0] < pair[1]));
+        for (value, bytes) in values.iter().zip(&encoded) {
+            let mut decoder = ByteDecoder::new(&bytes[..]);
+            assert_eq!(decoder.read_ordered_bytes().unwrap(), *value);
+            assert_eq!(decoder.read_ordered_u32().unwrap(), 7);
+        }
+    }
 }
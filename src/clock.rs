@@ -1,6 +1,21 @@
 use std::time::{Duration, SystemTime};
 
-fn adjust_system_time(offset: Duration) {
-    let mut system_time = SystemTime::now();
-    system_time = system_time.checked_add(offset).unwrap();
+pub fn adjust_system_time(offset: Duration) -> SystemTime {
+    SystemTime::now().checked_add(offset).unwrap()
+}
+
+/// Nanoseconds since the unix epoch, negative before 1970.
+pub fn to_nanos(time: SystemTime) -> i64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i64,
+        Err(err) => -(err.duration().as_nanos() as i64),
+    }
+}
+
+pub fn from_nanos(nanos: i64) -> SystemTime {
+    if nanos >= 0 {
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos as u64)
+    } else {
+        SystemTime::UNIX_EPOCH - Duration::from_nanos(nanos.unsigned_abs())
+    }
 }
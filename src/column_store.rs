@@ -1,10 +1,10 @@
 use std::collections::BTreeMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::time::SystemTime;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
-use crate::column_value::ColumnValue;
+use crate::byte_encoder::{ByteDecoder, ByteEncoder};
+use crate::column_value::{ColumnType, ColumnValue};
 
 // # person table
 // ("Name", "Date of birth", "Waist Size", "token")
@@ -14,91 +14,824 @@ use crate::column_value::ColumnValue;
 
 // ("Name" "John", "Mary", "Bob")
 
-struct ColumnStore {
+/// A row of a column: the series id, the timestamp, and the value, `None` for a point
+/// without the field.
+pub type ColumnRow = (u32, i64, Option<ColumnValue>);
+
+/// Version of the segment format, kept in the `format` file next to the segments.
+const FORMAT_VERSION: u32 = 3;
+
+/// Segments with fewer rows are merged into the next one written to their column, so a
+/// column has at most one segment below this size.
+pub const MIN_SEGMENT_ROWS: u64 = 4096;
+
+const FORMAT: &str = "format";
+
+/// Columns kept in a directory as segment files named `<id>.<sequence>.seg`, by a number
+/// given to each column. Column names are kept in the segments, so any name can be used.
+pub struct ColumnStore {
+    path: PathBuf,
     columns: BTreeMap<String, ColumnFile>,
+    /// Number given to the next column created.
+    next_id: u32,
+    /// Set when the directory held no columns of the current format, see `needs_rebuild`.
+    rebuild: bool,
 }
 
 impl ColumnStore {
-    fn open<P: AsRef<Path>>(path: P) -> std::io::Result<ColumnStore> {
-        let mut columns = BTreeMap::new();
-        for entry in std::fs::read_dir(path)? {
-            let entry = entry?;
-            let path = entry.path();
-            let column_name = path
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ColumnStore> {
+        let path = path.as_ref().to_owned();
+        std::fs::create_dir_all(&path)?;
+        let version = match std::fs::read_to_string(path.join(FORMAT)) {
+            Ok(version) => version.trim().parse().ok(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        if version != Some(FORMAT_VERSION) {
+            // Columns of an older format, or ones the database never wrote, are dropped
+            // for the owner to write again from its own records.
+            for entry in std::fs::read_dir(&path)? {
+                std::fs::remove_file(entry?.path())?;
+            }
+            return Ok(ColumnStore {
+                path,
+                columns: BTreeMap::new(),
+                next_id: 0,
+                rebuild: true,
+            });
+        }
+        let mut segments: BTreeMap<u32, Vec<(u64, PathBuf)>> = BTreeMap::new();
+        for entry in std::fs::read_dir(&path)? {
+            let path = entry?.path();
+            let file_name = path
                 .file_name()
                 .and_then(|s| s.to_str())
                 .unwrap_or_default()
                 .to_owned();
-            let column_file = ColumnFile::open(path)?;
-            // columns.insert(column_name, column_file);
+            if file_name.ends_with(".tmp") {
+                // A segment that was being written when the process stopped.
+                std::fs::remove_file(&path)?;
+            } else if let Some((id, sequence)) = parse_segment_name(&file_name) {
+                segments.entry(id).or_default().push((sequence, path));
+            }
         }
-        Ok(ColumnStore { columns })
+        let next_id = segments.last_key_value().map_or(0, |(id, _)| id + 1);
+        let mut columns = BTreeMap::new();
+        for (id, mut paths) in segments {
+            paths.sort();
+            let column_file = ColumnFile::open(&path, id, paths)?;
+            if columns.contains_key(&column_file.name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("column {} is kept twice", column_file.name),
+                ));
+            }
+            columns.insert(column_file.name.clone(), column_file);
+        }
+        Ok(ColumnStore {
+            path,
+            columns,
+            next_id,
+            rebuild: false,
+        })
+    }
+
+    /// Whether the store was opened without columns of the current format, and has to be
+    /// written in full before `rebuilt` is called.
+    pub fn needs_rebuild(&self) -> bool {
+        self.rebuild
+    }
+
+    /// Records that every column has been written in the current format. Until then the
+    /// store is dropped again when it is opened.
+    pub fn rebuilt(&mut self) -> io::Result<()> {
+        let tmp = self.path.join(format!("{FORMAT}.tmp"));
+        std::fs::write(&tmp, FORMAT_VERSION.to_string())?;
+        File::open(&tmp)?.sync_all()?;
+        std::fs::rename(&tmp, self.path.join(FORMAT))?;
+        self.rebuild = false;
+        Ok(())
     }
 
-    fn insert(
-        &mut self,
+    /// Writes `rows` to the column as a new segment. A new column is typed by the first
+    /// value in the rows, and is not created by rows without any.
+    pub fn append(&mut self, column_name: &str, rows: &[ColumnRow]) -> io::Result<()> {
+        if !self.columns.contains_key(column_name) {
+            let Some(column_type) = rows
+                .iter()
+                .find_map(|(_, _, value)| value.as_ref().map(ColumnValue::column_type))
+            else {
+                return Ok(());
+            };
+            let column_file = ColumnFile::new(&self.path, self.next_id, column_name, column_type);
+            self.columns.insert(column_name.to_owned(), column_file);
+            self.next_id += 1;
+        }
+        self.columns.get_mut(column_name).unwrap().append(rows)
+    }
+
+    pub fn column(&self, column_name: &str) -> Option<&ColumnFile> {
+        self.columns.get(column_name)
+    }
+
+    /// Names of the columns holding any value, in order.
+    pub fn column_names(&self) -> impl Iterator<Item = &str> {
+        self.columns.keys().map(String::as_str)
+    }
+
+    /// Type of the values in the column, `None` while nothing was written to it.
+    pub fn column_type(&self, column_name: &str) -> Option<ColumnType> {
+        self.column(column_name).map(ColumnFile::column_type)
+    }
+
+    /// Values of the column with timestamps from `start` to `end` inclusive, by time and
+    /// then series. Of the rows written for one series and timestamp the last one counts,
+    /// rows without a value are left out.
+    pub fn query(
+        &self,
         column_name: &str,
-        value: ColumnValue,
-        timestamp: (SystemTime, u64),
-    ) -> std::io::Result<()> {
-        let column_file = self
-            .columns
-            .entry(column_name.to_owned())
-            .or_insert_with(|| ColumnFile::create(column_name));
-        // column_file.write(value)
+        start: i64,
+        end: i64,
+    ) -> io::Result<Vec<(u32, i64, ColumnValue)>> {
+        match self.column(column_name) {
+            Some(column_file) => column_file.scan(start, end, None),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Like `query`, keeping only the values from `low` to `high` inclusive.
+    pub fn query_values(
+        &self,
+        column_name: &str,
+        start: i64,
+        end: i64,
+        low: &ColumnValue,
+        high: &ColumnValue,
+    ) -> io::Result<Vec<(u32, i64, ColumnValue)>> {
+        match self.column(column_name) {
+            Some(column_file) => column_file.scan(start, end, Some((low, high))),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+/// Splits a segment file name into the column id and the sequence number.
+fn parse_segment_name(file_name: &str) -> Option<(u32, u64)> {
+    let (id, sequence) = file_name.strip_suffix(".seg")?.split_once('.')?;
+    Some((id.parse().ok()?, sequence.parse().ok()?))
+}
+
+fn segment_path(dir: &Path, id: u32, sequence: u64) -> PathBuf {
+    dir.join(format!("{id:08}.{sequence:08}.seg"))
+}
+
+/// Length of the header of the segments of a column: the type of its values, and its
+/// name after its length.
+fn header_len(column_name: &str) -> u64 {
+    5 + column_name.len() as u64
+}
+
+/// Writes an immutable segment: the header, the rows, and the footer followed by its
+/// length. Rows are a series id and a timestamp, then 0 for a null or 1 and the value.
+/// The segment only appears under `path` once it is complete and synced.
+fn write_segment(
+    path: &Path,
+    column_type: ColumnType,
+    column_name: &str,
+    rows: &[ColumnRow],
+    footer: &SegmentFooter,
+) -> io::Result<()> {
+    let tmp = path.with_extension("seg.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    let mut encoder = ByteEncoder::new(&mut writer);
+    encoder.write_u8(column_type.into())?;
+    encoder.write_u32(column_name.len() as u32)?;
+    encoder.write_bytes(column_name.as_bytes())?;
+    for (series, timestamp, value) in rows {
+        encoder.write_u32(*series)?;
+        encoder.write_u64(*timestamp as u64)?;
+        match value {
+            Some(value) => {
+                encoder.write_u8(1)?;
+                value.encode_untyped(&mut encoder)?;
+            }
+            None => encoder.write_u8(0)?,
+        }
+    }
+    let mut encoded = ByteEncoder::new(vec![]);
+    footer.encode(&mut encoded)?;
+    encoder.write_bytes(&encoded.inner)?;
+    encoder.write_u32(encoded.inner.len() as u32)?;
+    let file = writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// The last of the rows for each series and timestamp, ordered by time and then series.
+fn latest_rows(rows: Vec<ColumnRow>) -> Vec<ColumnRow> {
+    let mut latest = BTreeMap::new();
+    for (series, timestamp, value) in rows {
+        latest.insert((timestamp, series), value);
+    }
+    latest
+        .into_iter()
+        .map(|((timestamp, series), value)| (series, timestamp, value))
+        .collect()
+}
+
+/// Summary of a segment at its end. Footers are read when a column is opened, so a query
+/// reads only the segments whose times and values may match it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentFooter {
+    pub min_time: i64,
+    pub max_time: i64,
+    /// Smallest and largest value, `None` when every row is null.
+    pub min_value: Option<ColumnValue>,
+    pub max_value: Option<ColumnValue>,
+    pub rows: u64,
+    pub nulls: u64,
+    /// Whether the times of the segment overlap an earlier one, whose rows it may then
+    /// replace.
+    pub shadows: bool,
+    /// Sequence of the segment merged into this one, its own if it merged none.
+    pub first_sequence: u64,
+}
+
+impl SegmentFooter {
+    fn of(rows: &[ColumnRow]) -> SegmentFooter {
+        let values = || rows.iter().filter_map(|(_, _, value)| value.as_ref());
+        let pick = |ordering| {
+            values()
+                .reduce(|a, b| {
+                    if b.partial_cmp(a) == Some(ordering) {
+                        b
+                    } else {
+                        a
+                    }
+                })
+                .cloned()
+        };
+        SegmentFooter {
+            min_time: rows
+                .iter()
+                .map(|(_, timestamp, _)| *timestamp)
+                .min()
+                .unwrap_or_default(),
+            max_time: rows
+                .iter()
+                .map(|(_, timestamp, _)| *timestamp)
+                .max()
+                .unwrap_or_default(),
+            min_value: pick(std::cmp::Ordering::Less),
+            max_value: pick(std::cmp::Ordering::Greater),
+            rows: rows.len() as u64,
+            nulls: (rows.len() - values().count()) as u64,
+            shadows: false,
+            first_sequence: 0,
+        }
+    }
+
+    fn encode(&self, encoder: &mut ByteEncoder<Vec<u8>>) -> io::Result<()> {
+        encoder.write_u64(self.min_time as u64)?;
+        encoder.write_u64(self.max_time as u64)?;
+        encoder.write_u64(self.rows)?;
+        encoder.write_u64(self.nulls)?;
+        encoder.write_u8(self.shadows.into())?;
+        encoder.write_u64(self.first_sequence)?;
+        for value in [&self.min_value, &self.max_value] {
+            match value {
+                Some(value) => {
+                    encoder.write_u8(1)?;
+                    value.encode(encoder)?;
+                }
+                None => encoder.write_u8(0)?,
+            }
+        }
         Ok(())
     }
 
-    fn query(&self, column_name: &str, start: u64, end: u64) -> Vec<u64> {
-        let column_file = self
-            .columns
-            .get(column_name)
-            .unwrap_or_else(|| panic!("Column not found: {}", column_name));
-        // column_file.query(start, end)
-        vec![]
+    fn decode(decoder: &mut ByteDecoder<&[u8]>) -> io::Result<SegmentFooter> {
+        let min_time = decoder.read_u64()? as i64;
+        let max_time = decoder.read_u64()? as i64;
+        let rows = decoder.read_u64()?;
+        let nulls = decoder.read_u64()?;
+        let shadows = decoder.read_u8()? != 0;
+        let first_sequence = decoder.read_u64()?;
+        let mut value = || match decoder.read_u8()? {
+            0 => Ok(None),
+            _ => ColumnValue::decode(decoder).map(Some),
+        };
+        Ok(SegmentFooter {
+            min_time,
+            max_time,
+            min_value: value()?,
+            max_value: value()?,
+            rows,
+            nulls,
+            shadows,
+            first_sequence,
+        })
+    }
+
+    /// Whether the segment may hold values at times from `start` to `end`, and between
+    /// `values` if given.
+    fn may_match(
+        &self,
+        start: i64,
+        end: i64,
+        values: Option<(&ColumnValue, &ColumnValue)>,
+    ) -> bool {
+        if self.max_time < start || self.min_time > end {
+            return false;
+        }
+        match (values, &self.min_value, &self.max_value) {
+            (None, _, _) => true,
+            (Some((low, high)), Some(min), Some(max)) => !(max < low || min > high),
+            (Some(_), _, _) => false,
+        }
     }
 }
-struct ColumnFile {
-    writer: BufWriter<File>,
-    reader: BufReader<File>,
+
+struct Segment {
+    path: PathBuf,
+    sequence: u64,
+    footer: SegmentFooter,
+}
+
+/// The values of one column, kept in immutable segments. Each `append` writes one, merging
+/// a segment below `MIN_SEGMENT_ROWS` into it.
+pub struct ColumnFile {
+    dir: PathBuf,
+    id: u32,
+    name: String,
+    column_type: ColumnType,
+    /// In the order they were written.
+    segments: Vec<Segment>,
 }
 
 impl ColumnFile {
-    fn open<P: AsRef<Path>>(path: P) -> std::io::Result<ColumnFile> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file.try_clone()?);
-        let writer = BufWriter::new(file);
-        Ok(ColumnFile { reader, writer })
-    }
-
-    fn create(name: &str) -> ColumnFile {
-        let path = Path::new(name);
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(path)
-            .unwrap();
-        let reader = BufReader::new(file.try_clone().unwrap());
-        let writer = BufWriter::new(file);
-        ColumnFile { reader, writer }
+    fn new(dir: &Path, id: u32, name: &str, column_type: ColumnType) -> ColumnFile {
+        ColumnFile {
+            dir: dir.to_owned(),
+            id,
+            name: name.to_owned(),
+            column_type,
+            segments: vec![],
+        }
+    }
+
+    /// Opens the column from its segment files, which all name it and hold values of one
+    /// type.
+    fn open(dir: &Path, id: u32, paths: Vec<(u64, PathBuf)>) -> io::Result<ColumnFile> {
+        let mut header = None;
+        let mut segments = Vec::with_capacity(paths.len());
+        for (sequence, path) in paths {
+            let mut file = File::open(&path)?;
+            let mut decoder = ByteDecoder::new(&mut file);
+            let segment_type = ColumnType::try_from(decoder.read_u8()?)?;
+            let len = decoder.read_u32()? as usize;
+            let name = String::from_utf8(decoder.read_bytes(len)?)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let (column_type, column_name) = header.get_or_insert((segment_type, name.clone()));
+            if (*column_type, column_name.as_str()) != (segment_type, name.as_str()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "segment {} holds {segment_type} values of column {name}",
+                        path.display()
+                    ),
+                ));
+            }
+            file.seek(SeekFrom::End(-4))?;
+            let len = ByteDecoder::new(&mut file).read_u32()? as i64;
+            file.seek(SeekFrom::End(-4 - len))?;
+            let footer = ByteDecoder::new(&mut file).read_bytes(len as usize)?;
+            let footer = SegmentFooter::decode(&mut ByteDecoder::new(footer.as_slice()))?;
+            segments.push(Segment {
+                path,
+                sequence,
+                footer,
+            });
+        }
+        // A merge cut short leaves the segments it merged behind.
+        let merges: Vec<_> = segments
+            .iter()
+            .map(|segment| segment.footer.first_sequence..segment.sequence)
+            .collect();
+        let (merged, segments): (Vec<_>, Vec<_>) = segments
+            .into_iter()
+            .partition(|segment| merges.iter().any(|merge| merge.contains(&segment.sequence)));
+        for segment in merged {
+            std::fs::remove_file(&segment.path)?;
+        }
+        // Columns are only kept with at least one segment.
+        let (column_type, name) = header.unwrap();
+        Ok(ColumnFile {
+            dir: dir.to_owned(),
+            id,
+            name,
+            column_type,
+            segments,
+        })
+    }
+
+    pub fn column_type(&self) -> ColumnType {
+        self.column_type
     }
 
-    fn write(&mut self, value: u64) -> std::io::Result<()> {
-        self.writer.write_all(&value.to_le_bytes())?;
-        self.writer.flush()
+    /// Footers of the segments in the order they were written.
+    pub fn footers(&self) -> impl Iterator<Item = &SegmentFooter> {
+        self.segments.iter().map(|segment| &segment.footer)
     }
 
-    fn query(&mut self, start: u64, end: u64) -> Vec<u64> {
-        let mut values = Vec::new();
-        self.reader.seek(SeekFrom::Start(start * 8)).unwrap();
-        let mut buf = [0; 8];
-        while self.reader.read_exact(&mut buf).is_ok() {
-            let value = u64::from_le_bytes(buf);
-            if value > end {
-                break;
+    /// Writes `rows` as a new segment, refusing values of another type than the
+    /// column's. A last segment below `MIN_SEGMENT_ROWS` is merged into the new one, and
+    /// removed once that is written.
+    pub fn append(&mut self, rows: &[ColumnRow]) -> io::Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        if let Some(value) = rows
+            .iter()
+            .filter_map(|(_, _, value)| value.as_ref())
+            .find(|value| value.column_type() != self.column_type)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "column {} holds {} values, not {}",
+                    self.name,
+                    self.column_type,
+                    value.column_type()
+                ),
+            ));
+        }
+        let small = match self.segments.last() {
+            Some(last) if last.footer.rows < MIN_SEGMENT_ROWS => self.segments.len() - 1,
+            _ => self.segments.len(),
+        };
+        let mut merged = vec![];
+        for segment in &self.segments[small..] {
+            merged.extend(self.read_rows(segment)?);
+        }
+        merged.extend_from_slice(rows);
+        let rows = latest_rows(merged);
+
+        let sequence = self.segments.last().map_or(0, |last| last.sequence + 1);
+        let path = segment_path(&self.dir, self.id, sequence);
+        let mut footer = SegmentFooter::of(&rows);
+        footer.first_sequence = self.segments.get(small).map_or(sequence, |s| s.sequence);
+        footer.shadows = self.segments[..small].iter().any(|segment| {
+            segment.footer.min_time <= footer.max_time && footer.min_time <= segment.footer.max_time
+        });
+        write_segment(&path, self.column_type, &self.name, &rows, &footer)?;
+        let merged: Vec<Segment> = self.segments.drain(small..).collect();
+        self.segments.push(Segment {
+            path,
+            sequence,
+            footer,
+        });
+        for segment in merged {
+            std::fs::remove_file(&segment.path)?;
+        }
+        Ok(())
+    }
+
+    /// Reads every row of a segment.
+    fn read_rows(&self, segment: &Segment) -> io::Result<Vec<ColumnRow>> {
+        let mut reader = BufReader::new(File::open(&segment.path)?);
+        reader.seek(SeekFrom::Start(header_len(&self.name)))?;
+        let mut decoder = ByteDecoder::new(reader);
+        (0..segment.footer.rows)
+            .map(|_| {
+                let series = decoder.read_u32()?;
+                let timestamp = decoder.read_u64()? as i64;
+                let value = match decoder.read_u8()? {
+                    0 => None,
+                    _ => Some(ColumnValue::decode_untyped(self.column_type, &mut decoder)?),
+                };
+                Ok((series, timestamp, value))
+            })
+            .collect()
+    }
+
+    /// Values at times from `start` to `end` and between `values` if given, reading
+    /// only the segments that may hold any. Segments are read from the last one back, so
+    /// the first row seen for a series and timestamp is the one that counts.
+    fn scan(
+        &self,
+        start: i64,
+        end: i64,
+        values: Option<(&ColumnValue, &ColumnValue)>,
+    ) -> io::Result<Vec<(u32, i64, ColumnValue)>> {
+        let mut rows: BTreeMap<(i64, u32), Option<ColumnValue>> = BTreeMap::new();
+        for segment in self.segments.iter().rev() {
+            // A segment replacing rows of earlier ones hides them whatever its values.
+            let values = values.filter(|_| !segment.footer.shadows);
+            if !segment.footer.may_match(start, end, values) {
+                continue;
+            }
+            let mut reader = BufReader::new(File::open(&segment.path)?);
+            reader.seek(SeekFrom::Start(header_len(&self.name)))?;
+            let mut decoder = ByteDecoder::new(reader);
+            for _ in 0..segment.footer.rows {
+                let series = decoder.read_u32()?;
+                let timestamp = decoder.read_u64()? as i64;
+                let present = decoder.read_u8()? != 0;
+                let in_range = (start..=end).contains(&timestamp);
+                let value = match self.column_type.width() {
+                    _ if !present => None,
+                    // Values out of range are skipped without being decoded.
+                    Some(width) if !in_range => {
+                        decoder.read_bytes(width)?;
+                        None
+                    }
+                    _ => Some(ColumnValue::decode_untyped(self.column_type, &mut decoder)?),
+                };
+                if in_range {
+                    rows.entry((timestamp, series)).or_insert(value);
+                }
             }
-            values.push(value);
         }
-        values
+        Ok(rows
+            .into_iter()
+            .filter_map(|((timestamp, series), value)| Some((series, timestamp, value?)))
+            .filter(|(_, _, value)| values.is_none_or(|(low, high)| low <= value && value <= high))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    fn open_rebuilt(dir: &Path) -> ColumnStore {
+        let mut store = ColumnStore::open(dir).unwrap();
+        if store.needs_rebuild() {
+            store.rebuilt().unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn columns_keep_their_values_typed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_rebuilt(dir.path());
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let columns = [
+            ("cpu.usage", ColumnValue::Float(0.5)),
+            ("cpu.count", ColumnValue::Integer(-3)),
+            ("cpu.ticks", ColumnValue::Unsigned(u64::MAX)),
+            ("cpu.up", ColumnValue::Boolean(true)),
+            ("cpu.host", ColumnValue::String("a".repeat(300))),
+            ("cpu.dump", ColumnValue::Blob(vec![0, 1, 2])),
+            ("cpu.boot", ColumnValue::Timestamp(time)),
+        ];
+        for timestamp in [30, 10, 20] {
+            for (column, value) in &columns {
+                store
+                    .append(column, &[(7, timestamp, Some(value.clone()))])
+                    .unwrap();
+            }
+        }
+        assert!(store
+            .append("cpu.usage", &[(7, 40, Some(ColumnValue::Integer(1)))])
+            .is_err());
+        // Rows without any value do not create a column.
+        store.append("cpu.empty", &[(7, 40, None)]).unwrap();
+        drop(store);
+
+        let store = ColumnStore::open(dir.path()).unwrap();
+        assert!(!store.needs_rebuild());
+        for (column, value) in &columns {
+            assert_eq!(
+                store.query(column, 15, 30).unwrap(),
+                vec![(7, 20, value.clone()), (7, 30, value.clone())],
+                "{column}"
+            );
+        }
+        assert!(store.column("cpu.empty").is_none());
+        assert!(store.query("cpu.missing", 0, 100).unwrap().is_empty());
+    }
+
+    #[test]
+    fn queries_read_only_segments_their_zone_maps_admit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_rebuilt(dir.path());
+        let rows = |times: std::ops::Range<i64>, base: i64| -> Vec<ColumnRow> {
+            times
+                .map(|time| {
+                    let value = (time % 10 != 0).then_some(ColumnValue::Integer(base + time % 10));
+                    (1, time, value)
+                })
+                .collect()
+        };
+        let size = MIN_SEGMENT_ROWS as i64;
+        store.append("cpu.usage", &rows(0..size, 0)).unwrap();
+        store
+            .append("cpu.usage", &rows(size..2 * size, 50))
+            .unwrap();
+        drop(store);
+
+        let store = ColumnStore::open(dir.path()).unwrap();
+        let footers: Vec<_> = store
+            .column("cpu.usage")
+            .unwrap()
+            .footers()
+            .cloned()
+            .collect();
+        assert_eq!(
+            footers[1],
+            SegmentFooter {
+                min_time: size,
+                max_time: 2 * size - 1,
+                min_value: Some(ColumnValue::Integer(51)),
+                max_value: Some(ColumnValue::Integer(59)),
+                rows: MIN_SEGMENT_ROWS,
+                nulls: (size..2 * size).filter(|time| time % 10 == 0).count() as u64,
+                shadows: false,
+                first_sequence: 1,
+            }
+        );
+
+        // Queries that cannot match the first segment never open it.
+        std::fs::remove_file(segment_path(dir.path(), 0, 0)).unwrap();
+        let values = store.query("cpu.usage", 6150, i64::MAX).unwrap();
+        assert_eq!(
+            values.len(),
+            (6150..2 * size).filter(|time| time % 10 != 0).count()
+        );
+        assert_eq!(values[0], (1, 6151, ColumnValue::Integer(51)));
+        let low = ColumnValue::Integer(58);
+        let values = store
+            .query_values("cpu.usage", 0, i64::MAX, &low, &ColumnValue::Integer(100))
+            .unwrap();
+        assert_eq!(
+            values.len(),
+            (size..2 * size).filter(|time| time % 10 >= 8).count()
+        );
+        assert!(store.query("cpu.usage", 0, i64::MAX).is_err());
+    }
+
+    #[test]
+    fn later_rows_replace_earlier_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_rebuilt(dir.path());
+        let value = |value| Some(ColumnValue::Integer(value));
+        let size = MIN_SEGMENT_ROWS as i64;
+        let mut rows: Vec<ColumnRow> = (0..size).map(|time| (1, time, value(1))).collect();
+        rows.push((2, 10, value(2)));
+        store.append("cpu.usage", &rows).unwrap();
+        // The same point rewritten, once without the field.
+        store
+            .append("cpu.usage", &[(1, 10, value(90)), (1, 20, None)])
+            .unwrap();
+        store.append("cpu.usage", &[(3, size, value(4))]).unwrap();
+
+        let footers: Vec<_> = store
+            .column("cpu.usage")
+            .unwrap()
+            .footers()
+            .map(|footer| (footer.rows, footer.shadows))
+            .collect();
+        assert_eq!(footers, vec![(MIN_SEGMENT_ROWS + 1, false), (3, true)]);
+        let mut expected = vec![
+            (1, 10, ColumnValue::Integer(90)),
+            (2, 10, ColumnValue::Integer(2)),
+        ];
+        expected.extend((11..20).map(|time| (1, time, ColumnValue::Integer(1))));
+        assert_eq!(store.query("cpu.usage", 10, 20).unwrap(), expected);
+        // The replaced value is out of range, yet it stays hidden.
+        let values = store
+            .query_values(
+                "cpu.usage",
+                10,
+                20,
+                &ColumnValue::Integer(0),
+                &ColumnValue::Integer(5),
+            )
+            .unwrap();
+        assert_eq!(values, expected[1..]);
+    }
+
+    #[test]
+    fn small_segments_are_merged_into_the_next() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_rebuilt(dir.path());
+        let value = |value| Some(ColumnValue::Integer(value));
+        for time in 0..10 {
+            store
+                .append(
+                    "cpu.usage",
+                    &[(1, time, value(time)), (2, time, value(time))],
+                )
+                .unwrap();
+        }
+        store.append("cpu.usage", &[(1, 0, value(100))]).unwrap();
+        let footers = |store: &ColumnStore| -> Vec<_> {
+            store
+                .column("cpu.usage")
+                .unwrap()
+                .footers()
+                .map(|footer| (footer.rows, footer.first_sequence))
+                .collect()
+        };
+        assert_eq!(footers(&store), vec![(20, 9)]);
+
+        // A segment at the minimum size is left as it is.
+        let size = MIN_SEGMENT_ROWS as i64;
+        let rows: Vec<ColumnRow> = (10..size).map(|time| (1, time, value(time))).collect();
+        store.append("cpu.usage", &rows).unwrap();
+        store.append("cpu.usage", &[(2, size, value(0))]).unwrap();
+        assert_eq!(footers(&store), vec![(MIN_SEGMENT_ROWS + 10, 10), (1, 12)]);
+
+        // A merge cut short leaves the segments it merged, removed on open.
+        let leftover = segment_path(dir.path(), 0, 10);
+        std::fs::copy(segment_path(dir.path(), 0, 12), &leftover).unwrap();
+        drop(store);
+        let store = ColumnStore::open(dir.path()).unwrap();
+        assert!(!leftover.exists());
+        assert_eq!(footers(&store), vec![(MIN_SEGMENT_ROWS + 10, 10), (1, 12)]);
+        assert_eq!(
+            store.query("cpu.usage", 0, 0).unwrap(),
+            vec![
+                (1, 0, ColumnValue::Integer(100)),
+                (2, 0, ColumnValue::Integer(0))
+            ]
+        );
+        assert_eq!(
+            store.query("cpu.usage", 0, i64::MAX).unwrap().len() as u64,
+            MIN_SEGMENT_ROWS + 11
+        );
+    }
+
+    #[test]
+    fn outdated_stores_are_cleared_for_a_rebuild() {
+        let dir = tempfile::tempdir().unwrap();
+        // A column of the first, single file format and a segment without series ids.
+        std::fs::write(dir.path().join("cpu.host"), b"\x02").unwrap();
+        std::fs::write(dir.path().join("cpu.usage.00000000.seg"), b"\x01").unwrap();
+
+        let mut store = ColumnStore::open(dir.path()).unwrap();
+        assert!(store.needs_rebuild());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        store
+            .append("cpu.usage", &[(1, 10, Some(ColumnValue::Float(1.0)))])
+            .unwrap();
+        drop(store);
+
+        // Without the rebuild finished it starts over.
+        let mut store = ColumnStore::open(dir.path()).unwrap();
+        assert!(store.needs_rebuild());
+        assert!(store.column("cpu.usage").is_none());
+        store
+            .append("cpu.usage", &[(1, 10, Some(ColumnValue::Float(1.0)))])
+            .unwrap();
+        store.rebuilt().unwrap();
+        std::fs::write(dir.path().join("00000000.00000001.seg.tmp"), b"partial").unwrap();
+        drop(store);
+
+        let store = ColumnStore::open(dir.path()).unwrap();
+        assert!(!store.needs_rebuild());
+        assert_eq!(store.column_names().collect::<Vec<_>>(), vec!["cpu.usage"]);
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["00000000.00000000.seg", "format"]);
+    }
+
+    #[test]
+    fn columns_may_have_any_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = open_rebuilt(dir.path());
+        let names = ["cpu.usage", "..", "../cpu", "/etc/passwd", "a\\b.c", ""];
+        for (value, name) in names.iter().enumerate() {
+            let value = Some(ColumnValue::Unsigned(value as u64));
+            store.append(name, &[(1, 10, value)]).unwrap();
+        }
+        drop(store);
+
+        let store = ColumnStore::open(dir.path()).unwrap();
+        for (value, name) in names.iter().enumerate() {
+            assert_eq!(
+                store.query(name, 0, 100).unwrap(),
+                vec![(1, 10, ColumnValue::Unsigned(value as u64))],
+                "{name}"
+            );
+        }
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), names.len() + 1);
+        assert_eq!(files[0], "00000000.00000000.seg");
+        assert_eq!(files[names.len()], "format");
     }
 }
@@ -1,8 +1,17 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{self, Read, Write};
 use std::time::SystemTime;
 
+use crate::byte_encoder::{ByteDecoder, ByteEncoder};
+use crate::clock;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ColumnValue {
     Integer(i64),
+    Unsigned(u64),
     Float(f64),
+    Boolean(bool),
     String(String),
     Blob(Vec<u8>),
     Timestamp(SystemTime),
@@ -19,33 +28,215 @@ impl ColumnValue {
     //     }
     // }
 
-    fn integer(self) -> Option<i64> {
+    pub fn integer(self) -> Option<i64> {
         match self {
             ColumnValue::Integer(val) => Some(val),
             _ => None,
         }
     }
 
-    fn float(self) -> Option<f64> {
+    pub fn unsigned(self) -> Option<u64> {
+        match self {
+            ColumnValue::Unsigned(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    pub fn float(self) -> Option<f64> {
         match self {
             ColumnValue::Float(val) => Some(val),
             _ => None,
         }
     }
 
-    fn string(self) -> Option<String> {
+    pub fn boolean(self) -> Option<bool> {
+        match self {
+            ColumnValue::Boolean(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    pub fn string(self) -> Option<String> {
         match self {
             ColumnValue::String(val) => Some(val),
             _ => None,
         }
     }
 
-    fn timestamp(self) -> Option<SystemTime> {
+    pub fn timestamp(self) -> Option<SystemTime> {
         match self {
             ColumnValue::Timestamp(val) => Some(val),
             _ => None,
         }
     }
+
+    pub fn column_type(&self) -> ColumnType {
+        match self {
+            ColumnValue::Integer(_) => ColumnType::Integer,
+            ColumnValue::Unsigned(_) => ColumnType::Unsigned,
+            ColumnValue::Float(_) => ColumnType::Float,
+            ColumnValue::Boolean(_) => ColumnType::Boolean,
+            ColumnValue::String(_) => ColumnType::String,
+            ColumnValue::Blob(_) => ColumnType::Blob,
+            ColumnValue::Timestamp(_) => ColumnType::Timestamp,
+        }
+    }
+
+    pub(crate) fn encode<W: Write>(&self, encoder: &mut ByteEncoder<W>) -> io::Result<()> {
+        encoder.write_u8(self.column_type().into())?;
+        self.encode_untyped(encoder)
+    }
+
+    pub(crate) fn decode<R: Read>(decoder: &mut ByteDecoder<R>) -> io::Result<ColumnValue> {
+        let column_type = ColumnType::try_from(decoder.read_u8()?)?;
+        ColumnValue::decode_untyped(column_type, decoder)
+    }
+
+    /// Writes the value without its type, for a column that records the type once.
+    pub(crate) fn encode_untyped<W: Write>(&self, encoder: &mut ByteEncoder<W>) -> io::Result<()> {
+        match self {
+            ColumnValue::Integer(val) => encoder.write_u64(*val as u64),
+            ColumnValue::Unsigned(val) => encoder.write_u64(*val),
+            ColumnValue::Float(val) => encoder.write_u64(val.to_bits()),
+            ColumnValue::Boolean(val) => encoder.write_u8(*val as u8),
+            ColumnValue::String(val) => {
+                encoder.write_u32(val.len() as u32)?;
+                encoder.write_bytes(val.as_bytes())
+            }
+            ColumnValue::Blob(val) => {
+                encoder.write_u32(val.len() as u32)?;
+                encoder.write_bytes(val)
+            }
+            ColumnValue::Timestamp(val) => encoder.write_u64(clock::to_nanos(*val) as u64),
+        }
+    }
+
+    /// Reads a value of `column_type` written by `encode_untyped`.
+    pub(crate) fn decode_untyped<R: Read>(
+        column_type: ColumnType,
+        decoder: &mut ByteDecoder<R>,
+    ) -> io::Result<ColumnValue> {
+        Ok(match column_type {
+            ColumnType::Integer => ColumnValue::Integer(decoder.read_u64()? as i64),
+            ColumnType::Unsigned => ColumnValue::Unsigned(decoder.read_u64()?),
+            ColumnType::Float => ColumnValue::Float(f64::from_bits(decoder.read_u64()?)),
+            ColumnType::Boolean => match decoder.read_u8()? {
+                0 => ColumnValue::Boolean(false),
+                1 => ColumnValue::Boolean(true),
+                byte => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("bad boolean value {byte}"),
+                    ))
+                }
+            },
+            ColumnType::String => {
+                let len = decoder.read_u32()? as usize;
+                let bytes = decoder.read_bytes(len)?;
+                let val = String::from_utf8(bytes)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                ColumnValue::String(val)
+            }
+            ColumnType::Blob => {
+                let len = decoder.read_u32()? as usize;
+                ColumnValue::Blob(decoder.read_bytes(len)?)
+            }
+            ColumnType::Timestamp => {
+                ColumnValue::Timestamp(clock::from_nanos(decoder.read_u64()? as i64))
+            }
+        })
+    }
+}
+
+/// Values compare within their type, values of different types are unordered.
+impl PartialOrd for ColumnValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (ColumnValue::Integer(a), ColumnValue::Integer(b)) => a.partial_cmp(b),
+            (ColumnValue::Unsigned(a), ColumnValue::Unsigned(b)) => a.partial_cmp(b),
+            (ColumnValue::Float(a), ColumnValue::Float(b)) => a.partial_cmp(b),
+            (ColumnValue::Boolean(a), ColumnValue::Boolean(b)) => a.partial_cmp(b),
+            (ColumnValue::String(a), ColumnValue::String(b)) => a.partial_cmp(b),
+            (ColumnValue::Blob(a), ColumnValue::Blob(b)) => a.partial_cmp(b),
+            (ColumnValue::Timestamp(a), ColumnValue::Timestamp(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+}
+
+/// Type of a `ColumnValue`, the one type of all values in a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Integer,
+    Unsigned,
+    Float,
+    Boolean,
+    String,
+    Blob,
+    Timestamp,
+}
+
+impl ColumnType {
+    /// Bytes every value of the type takes, `None` for types stored with a length prefix.
+    pub fn width(self) -> Option<usize> {
+        match self {
+            ColumnType::Integer
+            | ColumnType::Unsigned
+            | ColumnType::Float
+            | ColumnType::Timestamp => Some(8),
+            ColumnType::Boolean => Some(1),
+            ColumnType::String | ColumnType::Blob => None,
+        }
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ColumnType::Integer => "integer",
+            ColumnType::Unsigned => "unsigned",
+            ColumnType::Float => "float",
+            ColumnType::Boolean => "boolean",
+            ColumnType::String => "string",
+            ColumnType::Blob => "blob",
+            ColumnType::Timestamp => "timestamp",
+        };
+        f.write_str(name)
+    }
+}
+
+impl TryFrom<u8> for ColumnType {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> io::Result<Self> {
+        match value {
+            0 => Ok(ColumnType::Integer),
+            1 => Ok(ColumnType::Float),
+            2 => Ok(ColumnType::String),
+            3 => Ok(ColumnType::Blob),
+            4 => Ok(ColumnType::Timestamp),
+            5 => Ok(ColumnType::Unsigned),
+            6 => Ok(ColumnType::Boolean),
+            tag => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown column value type {tag}"),
+            )),
+        }
+    }
+}
+
+impl From<ColumnType> for u8 {
+    fn from(value: ColumnType) -> Self {
+        match value {
+            ColumnType::Integer => 0,
+            ColumnType::Float => 1,
+            ColumnType::String => 2,
+            ColumnType::Blob => 3,
+            ColumnType::Timestamp => 4,
+            ColumnType::Unsigned => 5,
+            ColumnType::Boolean => 6,
+        }
+    }
 }
 
 macro_rules! column_from_raw {
@@ -60,8 +251,11 @@ macro_rules! column_from_raw {
 
 column_from_raw!(i64, Integer);
 column_from_raw!(i32, Integer);
+column_from_raw!(u64, Unsigned);
+column_from_raw!(u32, Unsigned);
 column_from_raw!(f32, Float);
 column_from_raw!(f64, Float);
+column_from_raw!(bool, Boolean);
 column_from_raw!(String, String);
 column_from_raw!(&str, String);
 column_from_raw!(Vec<u8>, Blob);
@@ -0,0 +1,1566 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::byte_encoder::{ByteDecoder, ByteEncoder};
+use crate::clock;
+use crate::column_store::{ColumnRow, ColumnStore};
+use crate::column_value::{ColumnType, ColumnValue};
+use crate::errors::Result;
+use crate::line_protocol::{self, Line, ParseError};
+use crate::storage::b_tree::{self, Btree};
+use crate::storage::check::{check_file, Report};
+use crate::storage::pager::Pager;
+use crate::wal::{RecordKind, SyncPolicy, Tail, WriteAheadLog, WriteAheadLogReader};
+
+pub mod query;
+
+use query::Query;
+
+pub struct Config {
+    /// Directory holding the WAL, column files and B-tree storage.
+    pub cwd: PathBuf,
+    /// Size in bytes at which the WAL rolls over to a new segment.
+    pub wal_segment_size: u64,
+    /// Durability of writes that do not pick their own policy.
+    pub sync_policy: SyncPolicy,
+    /// Memory budget in bytes for B-tree pages cached across all series.
+    pub cache_size: usize,
+    /// How a point is combined with one already written for its series and timestamp.
+    pub conflict_policy: ConflictPolicy,
+    /// Whether integers written to a float field are stored as floats rather than
+    /// rejected as a field type conflict.
+    pub coerce_integers: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cwd: PathBuf::from("./solipsist"),
+            wal_segment_size: 8 * 1024 * 1024,
+            sync_policy: SyncPolicy::default(),
+            cache_size: 8 * 1024 * 1024,
+            conflict_policy: ConflictPolicy::default(),
+            coerce_integers: false,
+        }
+    }
+}
+
+/// What happens when a point is written for a series and timestamp that already has one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The new point replaces the existing one.
+    Overwrite,
+    /// The existing point is kept and the new one dropped.
+    Keep,
+    /// The field sets are merged, fields of the new point replacing existing ones.
+    #[default]
+    Merge,
+}
+
+impl ConflictPolicy {
+    fn resolve(self, existing: &mut FieldSet, fields: FieldSet) {
+        match self {
+            ConflictPolicy::Overwrite => *existing = fields,
+            ConflictPolicy::Keep => {}
+            ConflictPolicy::Merge => existing.extend(fields),
+        }
+    }
+
+    fn insert<K: Ord>(self, points: &mut BTreeMap<K, FieldSet>, key: K, fields: FieldSet) {
+        match points.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(fields);
+            }
+            Entry::Occupied(mut entry) => self.resolve(entry.get_mut(), fields),
+        }
+    }
+}
+
+/// A measurement and its tag set, sorted by tag key.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct SeriesKey {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+}
+
+impl SeriesKey {
+    fn from_line(line: &Line) -> Self {
+        let mut tags = line.tags.clone();
+        tags.sort();
+        tags.dedup_by(|a, b| a.0 == b.0);
+        SeriesKey {
+            measurement: line.measurement.clone(),
+            tags,
+        }
+    }
+}
+
+impl fmt::Display for SeriesKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", line_protocol::escape(&self.measurement, ", "))?;
+        for (key, value) in &self.tags {
+            write!(
+                f,
+                ",{}={}",
+                line_protocol::escape(key, ",= "),
+                line_protocol::escape(value, ",= ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub type FieldSet = BTreeMap<String, ColumnValue>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub series: SeriesKey,
+    /// Nanoseconds since the unix epoch.
+    pub timestamp: i64,
+    /// One value per entry in `QueryResult::columns`, `None` where the point lacks the field.
+    pub values: Vec<Option<ColumnValue>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Row>,
+}
+
+/// Most fields a measurement can have, as stored field sets count them in a `u16`.
+pub const MAX_FIELDS: usize = u16::MAX as usize;
+
+/// Longest field key in bytes, as stored field sets give its length in a `u16`.
+pub const MAX_FIELD_KEY_LEN: usize = u16::MAX as usize;
+
+/// A line of a batch that `write` did not store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// The line does not parse.
+    Parse(ParseError),
+    /// A field written with another type than it was first written with to its
+    /// measurement. The line is rejected whole.
+    FieldTypeConflict {
+        line: usize,
+        measurement: String,
+        field: String,
+        expected: ColumnType,
+        received: ColumnType,
+    },
+    /// A field key longer than `MAX_FIELD_KEY_LEN` bytes.
+    FieldKeyTooLong {
+        line: usize,
+        measurement: String,
+        len: usize,
+    },
+    /// New fields for a measurement that would then have more than `MAX_FIELDS`.
+    TooManyFields { line: usize, measurement: String },
+}
+
+impl Rejection {
+    /// The 1-based number of the rejected line in its batch.
+    pub fn line(&self) -> usize {
+        match self {
+            Rejection::Parse(err) => err.line,
+            Rejection::FieldTypeConflict { line, .. }
+            | Rejection::FieldKeyTooLong { line, .. }
+            | Rejection::TooManyFields { line, .. } => *line,
+        }
+    }
+}
+
+impl From<ParseError> for Rejection {
+    fn from(err: ParseError) -> Self {
+        Rejection::Parse(err)
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Parse(err) => err.fmt(f),
+            Rejection::FieldTypeConflict {
+                line,
+                measurement,
+                field,
+                expected,
+                received,
+            } => write!(
+                f,
+                "line {line}: field '{field}' of measurement '{measurement}' is {expected}, not {received}"
+            ),
+            Rejection::FieldKeyTooLong {
+                line,
+                measurement,
+                len,
+            } => write!(
+                f,
+                "line {line}: field key of {len} bytes for measurement '{measurement}', at most {MAX_FIELD_KEY_LEN} are kept"
+            ),
+            Rejection::TooManyFields { line, measurement } => write!(
+                f,
+                "line {line}: measurement '{measurement}' would have more than {MAX_FIELDS} fields"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Outcome of replaying the WAL when a database is opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryStats {
+    /// Records applied to the write buffer.
+    pub replayed: usize,
+    /// Records already durable in storage or whose payload could not be parsed.
+    pub skipped: usize,
+    /// Set when replay stopped at a torn or corrupt record, which is then discarded.
+    pub tail: Option<Tail>,
+}
+
+/// An embedded solipsistDB instance rooted at `Config::cwd`.
+pub struct SolipsistDB {
+    inner: Mutex<TimeSeriesDatabase>,
+    recovery: RecoveryStats,
+    sync_policy: SyncPolicy,
+}
+
+impl SolipsistDB {
+    /// Opens the database, replaying the WAL into the write buffer.
+    pub fn new(config: Config) -> Result<SolipsistDB> {
+        let sync_policy = config.sync_policy;
+        let (inner, recovery) = TimeSeriesDatabase::open(config)?;
+        Ok(SolipsistDB {
+            inner: Mutex::new(inner),
+            recovery,
+            sync_policy,
+        })
+    }
+
+    /// What recovery did when this instance was opened.
+    pub fn recovery(&self) -> &RecoveryStats {
+        &self.recovery
+    }
+
+    /// Writes a batch of line protocol. Valid lines are logged to the WAL and buffered,
+    /// rejected lines are returned without failing the rest of the batch.
+    pub fn write(&self, input: &str) -> Result<Vec<Rejection>> {
+        self.write_with(input, self.sync_policy)
+    }
+
+    /// Like `write`, but overrides the configured sync policy for this batch. The
+    /// database stays available to other writers while this one waits for its sync.
+    pub fn write_with(&self, input: &str, policy: SyncPolicy) -> Result<Vec<Rejection>> {
+        let (errors, sequence, sync) = {
+            let mut inner = self.inner.lock().unwrap();
+            let errors = inner.write(input)?;
+            (errors, inner.wal.last_sequence(), inner.wal.sync_handle())
+        };
+        sync.wait(sequence, policy)?;
+        Ok(errors)
+    }
+
+    pub fn query(&self, query: &str) -> Result<QueryResult> {
+        let query = query::parse(query)?;
+        self.inner.lock().unwrap().query(&query)
+    }
+
+    /// Moves buffered points into B-tree and column storage and checkpoints the WAL.
+    pub fn flush(&self) -> Result<()> {
+        self.inner.lock().unwrap().flush()
+    }
+
+    /// Flushes, then gives the free pages of the B-tree storage back to the file system.
+    /// Fails while snapshots are open.
+    pub fn vacuum(&self) -> Result<()> {
+        self.inner.lock().unwrap().vacuum()
+    }
+
+    /// Takes a snapshot of the points stored by the last flush. Reading it does not hold
+    /// up writes or flushes, and it sees each flush either entirely or not at all.
+    pub fn snapshot(&self) -> Snapshot {
+        let inner = self.inner.lock().unwrap();
+        Snapshot {
+            catalog: inner.catalog.clone(),
+            points: inner.points.snapshot(),
+        }
+    }
+}
+
+/// Points stored as of one flush, see `SolipsistDB::snapshot`.
+pub struct Snapshot {
+    catalog: Vec<SeriesKey>,
+    points: b_tree::Snapshot,
+}
+
+impl Snapshot {
+    /// Every point in the snapshot, by series id and then timestamp.
+    pub fn points(&self) -> impl Iterator<Item = Result<(&SeriesKey, i64, FieldSet)>> + '_ {
+        self.points.range(..).map(|pair| {
+            let pair = pair?;
+            let (id, timestamp) = from_point_key(&pair.key)?;
+            // Series are in the catalog before any of their points reach the tree.
+            let series = &self.catalog[id as usize];
+            Ok((series, timestamp, decode_fields(&pair.value)))
+        })
+    }
+}
+
+pub(crate) struct TimeSeriesDatabase {
+    /// Write buffer of points not yet flushed to storage, by series and timestamp.
+    data: BTreeMap<SeriesKey, BTreeMap<i64, FieldSet>>,
+    wal: WriteAheadLog,
+    columns: ColumnStore,
+    /// Directory of the points tree and the series catalog.
+    series_dir: PathBuf,
+    /// Every series ever flushed, a series' id is its index.
+    catalog: Vec<SeriesKey>,
+    series_ids: BTreeMap<SeriesKey, u32>,
+    pager: Arc<Pager>,
+    /// Flushed points of all series, keyed by `point_key`.
+    points: Btree,
+    /// Index of the flushed series by tag, keyed by `tag_key`.
+    tags: Btree,
+    /// Types of fields by measurement and field, as first written. Once a column is
+    /// created its type is the one it holds.
+    field_types: BTreeMap<String, BTreeMap<String, ColumnType>>,
+    conflict_policy: ConflictPolicy,
+    coerce_integers: bool,
+}
+
+impl TimeSeriesDatabase {
+    fn open(config: Config) -> Result<(TimeSeriesDatabase, RecoveryStats)> {
+        std::fs::create_dir_all(&config.cwd)?;
+        let wal_path = config.cwd.join("wal");
+        // Opening the log cuts off a damaged tail before the records are replayed.
+        let wal = WriteAheadLog::open(&wal_path, config.wal_segment_size)?;
+        let mut data = BTreeMap::new();
+        let mut field_types = BTreeMap::new();
+        let mut recovery = Self::recover(
+            &wal_path,
+            wal.last_checkpoint(),
+            config.conflict_policy,
+            &mut data,
+            &mut field_types,
+        )?;
+        recovery.tail = wal.tail();
+
+        let series_dir = config.cwd.join("series");
+        std::fs::create_dir_all(&series_dir)?;
+        let catalog: Vec<SeriesKey> = match std::fs::read(series_dir.join(CATALOG)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(std::io::Error::from)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+        let series_ids = catalog
+            .iter()
+            .enumerate()
+            .map(|(id, series)| (series.clone(), id as u32))
+            .collect();
+        let pager = Arc::new(Pager::new(config.cache_size));
+        let points = Btree::open(&pager, series_dir.join(POINTS))?;
+        let mut columns = ColumnStore::open(config.cwd.join("columns"))?;
+        if columns.needs_rebuild() {
+            rebuild_columns(&points, &catalog, &mut columns)?;
+        }
+        for column in columns.column_names() {
+            if let Some((measurement, field)) = split_column_name(column) {
+                // Columns are only kept with a type.
+                let column_type = columns.column_type(column).unwrap();
+                let fields = field_types.entry(measurement).or_default();
+                fields.insert(field.to_owned(), column_type);
+            }
+        }
+        let mut tags = Btree::open_index(&pager, series_dir.join(TAGS))?;
+        if tags.keys().next().is_none() {
+            // The catalog predates the index, or the index was lost. It is built from
+            // the catalog and reaches its file with the next flush.
+            let mut keys: Vec<Vec<u8>> = catalog
+                .iter()
+                .enumerate()
+                .flat_map(|(id, series)| series_tag_keys(series, id as u32))
+                .collect();
+            keys.sort_unstable();
+            tags.bulk_load(keys.into_iter().map(|key| (key, vec![])), 1.0)?;
+            tags.commit();
+        }
+
+        let db = TimeSeriesDatabase {
+            data,
+            wal,
+            columns,
+            series_dir,
+            catalog,
+            series_ids,
+            pager,
+            points,
+            tags,
+            field_types,
+            conflict_policy: config.conflict_policy,
+            coerce_integers: config.coerce_integers,
+        };
+        Ok((db, recovery))
+    }
+
+    /// Returns the id of `series`, registering it in the catalog first if it is new.
+    /// The catalog is replaced atomically and written before any point of the series
+    /// reaches the tree, so every id in the tree has a name.
+    fn series_id(&mut self, series: &SeriesKey) -> Result<u32> {
+        if let Some(id) = self.series_ids.get(series) {
+            return Ok(*id);
+        }
+        let id = self.catalog.len() as u32;
+        self.catalog.push(series.clone());
+        let tmp = self.series_dir.join(format!("{CATALOG}.tmp"));
+        // Serializing string keys cannot fail.
+        std::fs::write(&tmp, serde_json::to_vec(&self.catalog).unwrap())?;
+        std::fs::rename(&tmp, self.series_dir.join(CATALOG))?;
+        self.series_ids.insert(series.clone(), id);
+        Ok(id)
+    }
+
+    /// Replays data records after `checkpoint` into `data`, recording the types of their
+    /// fields in `field_types`. Records carry resolved timestamps and field types, and
+    /// every conflict policy gives the same result when a point is applied again, so
+    /// replaying twice is harmless.
+    fn recover(
+        path: &Path,
+        checkpoint: u64,
+        policy: ConflictPolicy,
+        data: &mut BTreeMap<SeriesKey, BTreeMap<i64, FieldSet>>,
+        field_types: &mut BTreeMap<String, BTreeMap<String, ColumnType>>,
+    ) -> Result<RecoveryStats> {
+        let mut stats = RecoveryStats::default();
+        for record in WriteAheadLogReader::open(path)? {
+            let record = record?;
+            if record.kind != RecordKind::Data {
+                continue;
+            }
+            if record.sequence <= checkpoint {
+                stats.skipped += 1;
+                continue;
+            }
+            let line = std::str::from_utf8(&record.data)
+                .ok()
+                .and_then(|line| line_protocol::parse_line(line).ok());
+            match line {
+                Some(line) => {
+                    let fields = field_types.entry(line.measurement.clone()).or_default();
+                    for (field, value) in &line.fields {
+                        fields.entry(field.clone()).or_insert(value.column_type());
+                    }
+                    Self::buffer(data, line, policy);
+                    stats.replayed += 1;
+                }
+                None => stats.skipped += 1,
+            }
+        }
+        Ok(stats)
+    }
+
+    fn buffer(
+        data: &mut BTreeMap<SeriesKey, BTreeMap<i64, FieldSet>>,
+        line: Line,
+        policy: ConflictPolicy,
+    ) {
+        let timestamp = line.timestamp.unwrap_or_default();
+        let points = data.entry(SeriesKey::from_line(&line)).or_default();
+        policy.insert(points, timestamp, line.fields.into_iter().collect());
+    }
+
+    fn write(&mut self, input: &str) -> Result<Vec<Rejection>> {
+        let mut errors = vec![];
+        for (number, parsed) in line_protocol::parse_numbered_lines(input) {
+            let mut line = match parsed {
+                Ok(line) => line,
+                Err(err) => {
+                    errors.push(err.into());
+                    continue;
+                }
+            };
+            if let Err(rejection) = self.check_fields(number, &mut line) {
+                errors.push(rejection);
+                continue;
+            }
+            // Lines without a timestamp are logged with the time they were received.
+            let timestamp = line
+                .timestamp
+                .unwrap_or_else(|| clock::to_nanos(SystemTime::now()));
+            line.timestamp = Some(timestamp);
+            self.wal.write(line.to_string().as_bytes())?;
+            Self::buffer(&mut self.data, line, self.conflict_policy);
+        }
+        Ok(errors)
+    }
+
+    /// Checks the fields of `line` against the types they were first written with,
+    /// coercing integers to floats if enabled, and records the types of new fields. A
+    /// line with a conflicting field, or one over the limits of `MAX_FIELDS` and
+    /// `MAX_FIELD_KEY_LEN`, is rejected whole.
+    fn check_fields(
+        &mut self,
+        number: usize,
+        line: &mut Line,
+    ) -> std::result::Result<(), Rejection> {
+        let known = self.field_types.get(&line.measurement);
+        let mut new_types = BTreeMap::new();
+        for (field, value) in &mut line.fields {
+            if field.len() > MAX_FIELD_KEY_LEN {
+                return Err(Rejection::FieldKeyTooLong {
+                    line: number,
+                    measurement: line.measurement.clone(),
+                    len: field.len(),
+                });
+            }
+            let received = value.column_type();
+            let expected = known
+                .and_then(|types| types.get(field))
+                .or_else(|| new_types.get(field))
+                .copied();
+            match (expected, &*value) {
+                (None, _) => {
+                    new_types.insert(field.clone(), received);
+                }
+                (Some(expected), _) if expected == received => {}
+                (Some(ColumnType::Float), ColumnValue::Integer(integer))
+                    if self.coerce_integers =>
+                {
+                    *value = ColumnValue::Float(*integer as f64);
+                }
+                (Some(expected), _) => {
+                    return Err(Rejection::FieldTypeConflict {
+                        line: number,
+                        measurement: line.measurement.clone(),
+                        field: field.clone(),
+                        expected,
+                        received,
+                    });
+                }
+            }
+        }
+        if known.map_or(0, BTreeMap::len) + new_types.len() > MAX_FIELDS {
+            return Err(Rejection::TooManyFields {
+                line: number,
+                measurement: line.measurement.clone(),
+            });
+        }
+        let fields = self
+            .field_types
+            .entry(line.measurement.clone())
+            .or_default();
+        fields.extend(new_types);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // Column rows are only written for points whose records are durable, so a crash
+        // before the trees commit replays every point the columns already hold.
+        let sync = self.wal.sync_handle();
+        sync.wait(self.wal.last_sequence(), SyncPolicy::Always)?;
+        let data = std::mem::take(&mut self.data);
+        // On failure the points go back into the buffer, the WAL still holds them too.
+        if let Err(err) = self.store(&data) {
+            self.points.rollback();
+            self.tags.rollback();
+            self.data = data;
+            return Err(err);
+        }
+        // Snapshots see the whole batch once it is committed, never a part of it.
+        self.points.commit();
+        self.tags.commit();
+        self.pager.flush()?;
+        // Everything logged so far is now durable in the trees.
+        self.wal.checkpoint(self.wal.last_sequence())?;
+        Ok(())
+    }
+
+    fn vacuum(&mut self) -> Result<()> {
+        self.flush()?;
+        self.points.vacuum()?;
+        self.tags.vacuum()?;
+        Ok(self.pager.flush()?)
+    }
+
+    /// Upserts the points into the trees, then writes the points as the trees now hold
+    /// them to the columns, before the trees commit. A flush that fails or is cut short
+    /// writes the same rows again when it is retried, and the later rows replace the
+    /// earlier ones.
+    fn store(&mut self, data: &BTreeMap<SeriesKey, BTreeMap<i64, FieldSet>>) -> Result<()> {
+        let mut stored = vec![];
+        for (series, points) in data {
+            let id = self.series_id(series)?;
+            // A series registered by a flush that failed may not be indexed yet.
+            for key in series_tag_keys(series, id) {
+                self.tags
+                    .upsert(&key, vec![], b_tree::ConflictPolicy::Keep)?;
+            }
+            for (timestamp, fields) in points {
+                let policy = self.conflict_policy;
+                let merge = |existing: &[u8], new: &[u8]| {
+                    let mut fields = decode_fields(existing);
+                    policy.resolve(&mut fields, decode_fields(new));
+                    encode_fields(&fields)
+                };
+                let tree_policy = match policy {
+                    ConflictPolicy::Overwrite => b_tree::ConflictPolicy::Overwrite,
+                    ConflictPolicy::Keep => b_tree::ConflictPolicy::Keep,
+                    ConflictPolicy::Merge => b_tree::ConflictPolicy::Merge(&merge),
+                };
+                let key = point_key(id, *timestamp);
+                let previous = self
+                    .points
+                    .upsert(&key, encode_fields(fields), tree_policy)?;
+                let (fields, replaced) = match previous {
+                    Some(previous) => {
+                        let previous = decode_fields(&previous.value);
+                        let mut resolved = previous.clone();
+                        policy.resolve(&mut resolved, fields.clone());
+                        if resolved == previous {
+                            continue;
+                        }
+                        (resolved, previous.into_keys().collect())
+                    }
+                    None => (fields.clone(), vec![]),
+                };
+                stored.push(StoredPoint {
+                    series: id,
+                    measurement: &series.measurement,
+                    timestamp: *timestamp,
+                    fields,
+                    replaced,
+                });
+            }
+        }
+        for (column, rows) in column_rows(&stored) {
+            self.columns.append(&column, &rows)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the points tree holds a point of `series` at `timestamp`.
+    fn is_flushed(&self, series: &SeriesKey, timestamp: i64) -> Result<bool> {
+        match self.series_ids.get(series) {
+            Some(id) => Ok(self.points.search(&point_key(*id, timestamp))?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    /// Flushed series of the queried measurement with all the queried tags, found
+    /// through the tag index.
+    fn matching_series(&self, query: &Query) -> Result<Vec<(&SeriesKey, u32)>> {
+        let Some(((key, value), rest)) = query.tags.split_first() else {
+            let first = SeriesKey {
+                measurement: query.measurement.clone(),
+                tags: vec![],
+            };
+            return Ok(self
+                .series_ids
+                .range(first..)
+                .take_while(|(series, _)| series.measurement == query.measurement)
+                .map(|(series, id)| (series, *id))
+                .collect());
+        };
+        let tagged = |key: &str, value: &str| -> Result<BTreeSet<u32>> {
+            self.tags
+                .prefix(&tag_prefix(&query.measurement, key, value))
+                .map(|pair| Ok(from_tag_key(&pair?.key)?))
+                .collect()
+        };
+        let mut ids = tagged(key, value)?;
+        for (key, value) in rest {
+            let other = tagged(key, value)?;
+            ids.retain(|id| other.contains(id));
+        }
+        Ok(ids
+            .into_iter()
+            .map(|id| (&self.catalog[id as usize], id))
+            .collect())
+    }
+
+    /// Reads the queried fields of the flushed points from their columns, whose segments
+    /// outside the time range are skipped, and combines them with the buffered points.
+    fn query(&self, query: &Query) -> Result<QueryResult> {
+        let mut points: BTreeMap<(i64, &SeriesKey), FieldSet> = BTreeMap::new();
+        let matches = |series: &SeriesKey| {
+            series.measurement == query.measurement && query.matches_tags(&series.tags)
+        };
+
+        let series: BTreeMap<u32, &SeriesKey> = self
+            .matching_series(query)?
+            .into_iter()
+            .map(|(series, id)| (id, series))
+            .collect();
+        if let (false, Some((start, end))) = (series.is_empty(), query.time_range()) {
+            let fields: Vec<&str> = if query.fields.is_empty() {
+                self.columns
+                    .column_names()
+                    .filter_map(split_column_name)
+                    .filter(|(measurement, _)| *measurement == query.measurement)
+                    .map(|(_, field)| field)
+                    .collect()
+            } else {
+                query.fields.iter().map(String::as_str).collect()
+            };
+            for field in fields {
+                let column = column_name(&query.measurement, field);
+                for (id, timestamp, value) in self.columns.query(&column, start, end)? {
+                    if let Some(series) = series.get(&id) {
+                        let fields = points.entry((timestamp, *series)).or_default();
+                        fields.insert(field.to_owned(), value);
+                    }
+                }
+            }
+        }
+        for (series, buffered) in self.data.iter().filter(|(s, _)| matches(s)) {
+            for (timestamp, fields) in buffered {
+                if !query.contains_time(*timestamp) {
+                    continue;
+                }
+                let key = (*timestamp, series);
+                // The columns only tell about points with a queried field, a point kept
+                // whole may lack them all.
+                if self.conflict_policy == ConflictPolicy::Keep
+                    && !points.contains_key(&key)
+                    && self.is_flushed(series, *timestamp)?
+                {
+                    continue;
+                }
+                self.conflict_policy
+                    .insert(&mut points, key, fields.clone());
+            }
+        }
+
+        let columns = if query.fields.is_empty() {
+            let mut columns: Vec<String> =
+                points.values().flat_map(|f| f.keys().cloned()).collect();
+            columns.sort();
+            columns.dedup();
+            columns
+        } else {
+            query.fields.clone()
+        };
+        let rows = points
+            .into_iter()
+            .filter(|(_, fields)| columns.iter().any(|c| fields.contains_key(c)))
+            .map(|((timestamp, series), mut fields)| Row {
+                series: series.clone(),
+                timestamp,
+                values: columns.iter().map(|c| fields.remove(c)).collect(),
+            })
+            .collect();
+        Ok(QueryResult { columns, rows })
+    }
+}
+
+/// Checks the B-tree files of the database rooted at `cwd` as of its last flush, see
+/// `check_file`.
+pub fn check<P: AsRef<Path>>(cwd: P) -> Result<Vec<(PathBuf, Report)>> {
+    let series_dir = cwd.as_ref().join("series");
+    [POINTS, TAGS]
+        .into_iter()
+        .map(|name| {
+            let path = series_dir.join(name);
+            let report = check_file(&path)?;
+            Ok((path, report))
+        })
+        .collect()
+}
+
+/// Name of the column holding `field` of `measurement`. Dots and backslashes in the
+/// measurement are escaped, so the first bare dot ends it, see `split_column_name`.
+fn column_name(measurement: &str, field: &str) -> String {
+    let mut name = String::with_capacity(measurement.len() + field.len() + 1);
+    for c in measurement.chars() {
+        if matches!(c, '.' | '\\') {
+            name.push('\\');
+        }
+        name.push(c);
+    }
+    name.push('.');
+    name.push_str(field);
+    name
+}
+
+/// The measurement and the field of a column named by `column_name`.
+fn split_column_name(column: &str) -> Option<(String, &str)> {
+    let mut measurement = String::new();
+    let mut chars = column.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' => measurement.push(chars.next()?.1),
+            '.' => return Some((measurement, &column[idx + 1..])),
+            c => measurement.push(c),
+        }
+    }
+    None
+}
+
+/// A point as a flush leaves it in the points tree.
+struct StoredPoint<'a> {
+    series: u32,
+    measurement: &'a str,
+    timestamp: i64,
+    fields: FieldSet,
+    /// Fields the point had before, which its rows have to replace.
+    replaced: Vec<String>,
+}
+
+/// Rows of the stored points by column. A point has a row in every column of its
+/// measurement that any of the points has or had a field in, null for fields it lacks,
+/// so its rows replace all earlier ones for the same series and timestamp.
+fn column_rows(points: &[StoredPoint]) -> BTreeMap<String, Vec<ColumnRow>> {
+    let mut measurement_fields: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for point in points {
+        let fields = measurement_fields.entry(point.measurement).or_default();
+        fields.extend(
+            point
+                .fields
+                .keys()
+                .chain(&point.replaced)
+                .map(String::as_str),
+        );
+    }
+    let mut column_rows: BTreeMap<String, Vec<ColumnRow>> = BTreeMap::new();
+    for point in points {
+        for field in &measurement_fields[point.measurement] {
+            let value = point.fields.get(*field).cloned();
+            column_rows
+                .entry(column_name(point.measurement, field))
+                .or_default()
+                .push((point.series, point.timestamp, value));
+        }
+    }
+    column_rows
+}
+
+/// Points read from the tree at a time while the columns are rebuilt.
+const REBUILD_BATCH: usize = 64 * 1024;
+
+/// Writes the columns anew from the points tree, for a column store that was lost or
+/// written in an older format. Each batch of points adds a segment to the columns of its
+/// fields.
+fn rebuild_columns(points: &Btree, catalog: &[SeriesKey], columns: &mut ColumnStore) -> Result<()> {
+    let mut batch = vec![];
+    for pair in points.range(..) {
+        let pair = pair?;
+        let (series, timestamp) = from_point_key(&pair.key)?;
+        batch.push(StoredPoint {
+            series,
+            // Series are in the catalog before any of their points reach the tree.
+            measurement: &catalog[series as usize].measurement,
+            timestamp,
+            fields: decode_fields(&pair.value),
+            replaced: vec![],
+        });
+        if batch.len() == REBUILD_BATCH {
+            append_rebuilt(columns, &batch)?;
+            batch.clear();
+        }
+    }
+    append_rebuilt(columns, &batch)?;
+    Ok(columns.rebuilt()?)
+}
+
+/// Appends the rows of points read back from the tree. Values of another type than the
+/// column's, which only points written before field types were checked can hold, are
+/// left out.
+fn append_rebuilt(columns: &mut ColumnStore, points: &[StoredPoint]) -> Result<()> {
+    for (column, mut rows) in column_rows(points) {
+        let column_type = columns.column_type(&column).or_else(|| {
+            rows.iter()
+                .find_map(|(_, _, value)| value.as_ref().map(ColumnValue::column_type))
+        });
+        rows.retain(|(_, _, value)| {
+            value
+                .as_ref()
+                .is_none_or(|value| Some(value.column_type()) == column_type)
+        });
+        columns.append(&column, &rows)?;
+    }
+    Ok(())
+}
+
+const CATALOG: &str = "catalog.json";
+const POINTS: &str = "points.db";
+const TAGS: &str = "tags.db";
+
+/// Key of a point in the points tree, ordered by series id and then timestamp so each
+/// series is a contiguous run of the tree.
+fn point_key(series: u32, timestamp: i64) -> Vec<u8> {
+    let mut encoder = ByteEncoder::new(Vec::with_capacity(12));
+    // Writes into a Vec cannot fail.
+    encoder.write_ordered_u32(series).unwrap();
+    encoder.write_ordered_i64(timestamp).unwrap();
+    encoder.inner
+}
+
+fn from_point_key(key: &[u8]) -> std::io::Result<(u32, i64)> {
+    let mut decoder = ByteDecoder::new(key);
+    Ok((decoder.read_ordered_u32()?, decoder.read_ordered_i64()?))
+}
+
+/// Start of the keys in the tag index of the series of `measurement` with a tag.
+fn tag_prefix(measurement: &str, key: &str, value: &str) -> Vec<u8> {
+    let mut encoder = ByteEncoder::new(vec![]);
+    // Writes into a Vec cannot fail.
+    encoder.write_ordered_bytes(measurement.as_bytes()).unwrap();
+    encoder.write_ordered_bytes(key.as_bytes()).unwrap();
+    encoder.write_ordered_bytes(value.as_bytes()).unwrap();
+    encoder.inner
+}
+
+/// Key of a series in the tag index, one for each of its tags. The series with a tag
+/// are a contiguous run of the index, starting with its `tag_prefix`.
+fn tag_key(measurement: &str, key: &str, value: &str, series: u32) -> Vec<u8> {
+    let mut encoder = ByteEncoder::new(tag_prefix(measurement, key, value));
+    encoder.write_ordered_u32(series).unwrap();
+    encoder.inner
+}
+
+fn from_tag_key(key: &[u8]) -> std::io::Result<u32> {
+    let mut decoder = ByteDecoder::new(key);
+    for _ in 0..3 {
+        decoder.read_ordered_bytes()?;
+    }
+    decoder.read_ordered_u32()
+}
+
+fn series_tag_keys(series: &SeriesKey, id: u32) -> impl Iterator<Item = Vec<u8>> + '_ {
+    series
+        .tags
+        .iter()
+        .map(move |(key, value)| tag_key(&series.measurement, key, value, id))
+}
+
+fn encode_fields(fields: &FieldSet) -> Vec<u8> {
+    let mut encoder = ByteEncoder::new(vec![]);
+    // Writes into a Vec cannot fail, and `check_fields` keeps both counts in range.
+    encoder
+        .write_u16(u16::try_from(fields.len()).expect("at most MAX_FIELDS fields"))
+        .unwrap();
+    for (name, value) in fields {
+        let len = u16::try_from(name.len()).expect("at most MAX_FIELD_KEY_LEN bytes");
+        encoder.write_u16(len).unwrap();
+        encoder.write_bytes(name.as_bytes()).unwrap();
+        value.encode(&mut encoder).unwrap();
+    }
+    encoder.inner
+}
+
+fn decode_fields(bytes: &[u8]) -> FieldSet {
+    let mut decoder = ByteDecoder::new(Cursor::new(bytes));
+    let mut fields = FieldSet::new();
+    // Field sets are only ever decoded from bytes produced by `encode_fields`.
+    let count = decoder.read_u16().unwrap();
+    for _ in 0..count {
+        let len = decoder.read_u16().unwrap() as usize;
+        let name = String::from_utf8(decoder.read_bytes(len).unwrap()).unwrap();
+        fields.insert(name, ColumnValue::decode(&mut decoder).unwrap());
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::errors::Error;
+
+    #[test]
+    fn write_and_query() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = SolipsistDB::new(Config {
+            cwd: dir.path().to_owned(),
+            ..Config::default()
+        })
+        .unwrap();
+        let errors = db
+            .write(
+                "temperature,location=office value=72.5 10\n\
+                 temperature,location=garage value=50.1 11\n\
+                 temperature,location=office value=73.0,note=\"warm\" 20\n\
+                 temperature,location=office value= 30",
+            )
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line(), 4);
+
+        db.flush().unwrap();
+        {
+            // Points without the note have a null in its column.
+            let inner = db.inner.lock().unwrap();
+            let note = inner.columns.column("temperature.note").unwrap();
+            let footers: Vec<_> = note.footers().map(|f| (f.rows, f.nulls)).collect();
+            assert_eq!(footers, vec![(3, 2)]);
+        }
+        db.write("temperature,location=office value=74 30").unwrap();
+
+        let result = db
+            .query("SELECT value FROM temperature WHERE location = 'office' AND time > 10")
+            .unwrap();
+        assert_eq!(result.columns, vec!["value".to_owned()]);
+        let values: Vec<_> = result
+            .rows
+            .iter()
+            .map(|row| (row.timestamp, row.values.clone()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                (20, vec![Some(ColumnValue::Float(73.0))]),
+                (30, vec![Some(ColumnValue::Float(74.0))]),
+            ]
+        );
+
+        let result = db.query("SELECT * FROM temperature").unwrap();
+        assert_eq!(result.columns, vec!["note".to_owned(), "value".to_owned()]);
+        assert_eq!(result.rows.len(), 4);
+    }
+
+    #[test]
+    fn recovers_buffered_points_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = || Config {
+            cwd: dir.path().to_owned(),
+            ..Config::default()
+        };
+        let db = SolipsistDB::new(config()).unwrap();
+        assert_eq!(db.recovery(), &RecoveryStats::default());
+        db.write("cpu,host=a usage=1 10\ncpu,host=a usage=2 20\ncpu,host=b usage=3 20")
+            .unwrap();
+        drop(db);
+
+        // Reopening twice replays the same records into the same state.
+        for _ in 0..2 {
+            let db = SolipsistDB::new(config()).unwrap();
+            assert_eq!(
+                db.recovery(),
+                &RecoveryStats {
+                    replayed: 3,
+                    skipped: 0,
+                    tail: None
+                }
+            );
+            let result = db.query("SELECT usage FROM cpu WHERE host = 'a'").unwrap();
+            let timestamps: Vec<_> = result.rows.iter().map(|row| row.timestamp).collect();
+            assert_eq!(timestamps, vec![10, 20]);
+        }
+    }
+
+    #[test]
+    fn flushed_points_survive_reopen_without_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = || Config {
+            cwd: dir.path().to_owned(),
+            ..Config::default()
+        };
+        let db = SolipsistDB::new(config()).unwrap();
+        db.write("cpu,host=a usage=1 10\ncpu,host=b usage=2 20")
+            .unwrap();
+        db.flush().unwrap();
+        db.write("cpu,host=a usage=3 30").unwrap();
+        drop(db);
+
+        let db = SolipsistDB::new(config()).unwrap();
+        assert_eq!(db.recovery().replayed, 1);
+        let result = db.query("SELECT usage FROM cpu").unwrap();
+        let points: Vec<_> = result
+            .rows
+            .iter()
+            .map(|row| (row.series.to_string(), row.timestamp))
+            .collect();
+        assert_eq!(
+            points,
+            vec![
+                ("cpu,host=a".to_owned(), 10),
+                ("cpu,host=b".to_owned(), 20),
+                ("cpu,host=a".to_owned(), 30),
+            ]
+        );
+    }
+
+    #[test]
+    fn large_string_fields_survive_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = || Config {
+            cwd: dir.path().to_owned(),
+            ..Config::default()
+        };
+        let dump = "x".repeat(50_000);
+        let db = SolipsistDB::new(config()).unwrap();
+        db.write(&format!("crash,host=a dump=\"{dump}\",size=50000i 10"))
+            .unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        let db = SolipsistDB::new(config()).unwrap();
+        let result = db.query("SELECT dump, size FROM crash").unwrap();
+        assert_eq!(
+            result.rows[0].values,
+            vec![
+                Some(ColumnValue::String(dump.clone())),
+                Some(ColumnValue::Integer(50_000))
+            ]
+        );
+        let inner = db.inner.lock().unwrap();
+        assert_eq!(
+            inner.columns.query("crash.dump", 0, 10).unwrap(),
+            vec![(0, 10, ColumnValue::String(dump))]
+        );
+    }
+
+    #[test]
+    fn unsigned_and_boolean_fields_survive_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = || Config {
+            cwd: dir.path().to_owned(),
+            ..Config::default()
+        };
+        let db = SolipsistDB::new(config()).unwrap();
+        db.write("plc,line=1 counter=18446744073709551615u,running=true 10\nplc,line=1 counter=7u,running=f 20")
+            .unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        let db = SolipsistDB::new(config()).unwrap();
+        let result = db.query("SELECT counter, running FROM plc").unwrap();
+        let values: Vec<_> = result.rows.iter().map(|row| row.values.clone()).collect();
+        assert_eq!(
+            values,
+            vec![
+                vec![
+                    Some(ColumnValue::Unsigned(u64::MAX)),
+                    Some(ColumnValue::Boolean(true))
+                ],
+                vec![
+                    Some(ColumnValue::Unsigned(7)),
+                    Some(ColumnValue::Boolean(false))
+                ],
+            ]
+        );
+        assert!(values[1][0] < values[0][0]);
+    }
+
+    #[test]
+    fn rejects_fields_over_the_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = |name: &str| {
+            SolipsistDB::new(Config {
+                cwd: dir.path().join(name),
+                ..Config::default()
+            })
+            .unwrap()
+        };
+        let db = open("wide");
+        let fields: Vec<String> = (0..MAX_FIELDS).map(|idx| format!("f{idx}=1i")).collect();
+        let errors = db.write(&format!("wide {} 10", fields.join(","))).unwrap();
+        assert!(errors.is_empty());
+        let errors = db.write("wide f0=2i 20\nwide extra=1i 20").unwrap();
+        assert_eq!(
+            errors,
+            vec![Rejection::TooManyFields {
+                line: 2,
+                measurement: "wide".to_owned()
+            }]
+        );
+
+        // The long key is kept whole, in the tree and in the column named after it.
+        let db = open("long");
+        let long = "k".repeat(MAX_FIELD_KEY_LEN);
+        let errors = db
+            .write(&format!("cpu {long}=1i 10\ncpu {long}k=1i 10"))
+            .unwrap();
+        assert_eq!(
+            errors,
+            vec![Rejection::FieldKeyTooLong {
+                line: 2,
+                measurement: "cpu".to_owned(),
+                len: MAX_FIELD_KEY_LEN + 1
+            }]
+        );
+        db.flush().unwrap();
+        let result = db.query("SELECT * FROM cpu").unwrap();
+        assert_eq!(result.columns, vec![long]);
+        assert_eq!(result.rows[0].values, vec![Some(ColumnValue::Integer(1))]);
+    }
+
+    #[test]
+    fn rejects_fields_written_with_another_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = || Config {
+            cwd: dir.path().to_owned(),
+            ..Config::default()
+        };
+        let conflict = |line, field: &str, expected, received| Rejection::FieldTypeConflict {
+            line,
+            measurement: "cpu".to_owned(),
+            field: field.to_owned(),
+            expected,
+            received,
+        };
+        let db = SolipsistDB::new(config()).unwrap();
+        let errors = db
+            .write("cpu usage=1 10\ncpu usage=2i 20\nmem usage=2i 20\ncpu host=\"a\",usage=true 30")
+            .unwrap();
+        assert_eq!(
+            errors,
+            vec![
+                conflict(2, "usage", ColumnType::Float, ColumnType::Integer),
+                conflict(4, "usage", ColumnType::Float, ColumnType::Boolean),
+            ]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "line 2: field 'usage' of measurement 'cpu' is float, not integer"
+        );
+        // A rejected line records none of its fields.
+        let errors = db.write("cpu host=1i 40").unwrap();
+        assert!(errors.is_empty());
+        db.flush().unwrap();
+        drop(db);
+
+        // Types stick once flushed, and buffered types are recovered from the WAL.
+        let db = SolipsistDB::new(config()).unwrap();
+        db.write("cpu idle=1u 50").unwrap();
+        drop(db);
+        let db = SolipsistDB::new(config()).unwrap();
+        let errors = db.write("cpu usage=\"high\" 60\ncpu idle=1i 60").unwrap();
+        assert_eq!(
+            errors,
+            vec![
+                conflict(1, "usage", ColumnType::Float, ColumnType::String),
+                conflict(2, "idle", ColumnType::Unsigned, ColumnType::Integer),
+            ]
+        );
+        let result = db.query("SELECT host, usage FROM cpu").unwrap();
+        assert_eq!(result.rows.len(), 2);
+    }
+
+    #[test]
+    fn integers_coerce_to_float_fields_when_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = SolipsistDB::new(Config {
+            cwd: dir.path().to_owned(),
+            coerce_integers: true,
+            ..Config::default()
+        })
+        .unwrap();
+        let errors = db
+            .write("cpu usage=1.5 10\ncpu usage=2i 20\ncpu count=1i 10\ncpu count=2 20")
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line(), 4);
+        db.flush().unwrap();
+        let result = db.query("SELECT usage FROM cpu").unwrap();
+        let values: Vec<_> = result.rows.iter().map(|row| row.values.clone()).collect();
+        assert_eq!(
+            values,
+            vec![
+                vec![Some(ColumnValue::Float(1.5))],
+                vec![Some(ColumnValue::Float(2.0))]
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_points_follow_conflict_policy() {
+        let cases = [
+            (
+                ConflictPolicy::Overwrite,
+                vec![None, Some(ColumnValue::Float(3.0))],
+            ),
+            (
+                ConflictPolicy::Keep,
+                vec![Some(ColumnValue::Float(1.0)), Some(ColumnValue::Float(2.0))],
+            ),
+            (
+                ConflictPolicy::Merge,
+                vec![Some(ColumnValue::Float(1.0)), Some(ColumnValue::Float(3.0))],
+            ),
+        ];
+        for (conflict_policy, expected) in cases {
+            let dir = tempfile::tempdir().unwrap();
+            let db = SolipsistDB::new(Config {
+                cwd: dir.path().to_owned(),
+                conflict_policy,
+                ..Config::default()
+            })
+            .unwrap();
+            // The same point is written before and after a flush and twice in a batch.
+            db.write("cpu a=1,b=2 10").unwrap();
+            db.flush().unwrap();
+            db.write("cpu b=3 10").unwrap();
+            let result = db.query("SELECT a, b FROM cpu").unwrap();
+            assert_eq!(result.rows[0].values, expected, "{conflict_policy:?}");
+            db.flush().unwrap();
+            let result = db.query("SELECT a, b FROM cpu").unwrap();
+            assert_eq!(result.rows[0].values, expected, "{conflict_policy:?}");
+            // A field the flushed point lacks only shows if the new point is not dropped.
+            db.write("cpu c=4 10").unwrap();
+            let result = db.query("SELECT c FROM cpu").unwrap();
+            let kept = conflict_policy == ConflictPolicy::Keep;
+            assert_eq!(result.rows.is_empty(), kept, "{conflict_policy:?}");
+
+            db.write("cpu a=1,b=2 20\ncpu b=3 20").unwrap();
+            let result = db.query("SELECT a, b FROM cpu WHERE time = 20").unwrap();
+            assert_eq!(result.rows[0].values, expected, "{conflict_policy:?}");
+        }
+    }
+
+    #[test]
+    fn columns_hold_points_as_the_tree_stores_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = |conflict_policy| Config {
+            cwd: dir.path().to_owned(),
+            conflict_policy,
+            ..Config::default()
+        };
+        let column = |db: &SolipsistDB, column: &str| {
+            let inner = db.inner.lock().unwrap();
+            let values = inner.columns.query(column, 0, 100).unwrap();
+            // The segment each one merged, which changes with every write to the column.
+            let segments: Vec<_> = inner
+                .columns
+                .column(column)
+                .map(|c| c.footers().map(|footer| footer.first_sequence).collect())
+                .unwrap_or_default();
+            (values, segments)
+        };
+        let db = SolipsistDB::new(config(ConflictPolicy::Overwrite)).unwrap();
+        db.write("cpu,host=a a=1,b=2 10\ncpu,host=b a=5 10")
+            .unwrap();
+        db.flush().unwrap();
+        db.write("cpu,host=a b=3 10").unwrap();
+        db.flush().unwrap();
+        let float = ColumnValue::Float;
+        assert_eq!(column(&db, "cpu.a"), (vec![(1, 10, float(5.0))], vec![0]));
+        assert_eq!(column(&db, "cpu.b"), (vec![(0, 10, float(3.0))], vec![0]));
+        drop(db);
+
+        // A point kept as it is writes no rows.
+        let db = SolipsistDB::new(config(ConflictPolicy::Keep)).unwrap();
+        db.write("cpu,host=a a=9 10").unwrap();
+        db.flush().unwrap();
+        assert_eq!(column(&db, "cpu.a").1, vec![0]);
+        drop(db);
+
+        // Columns that are gone are written again from the tree.
+        std::fs::remove_dir_all(dir.path().join("columns")).unwrap();
+        let db = SolipsistDB::new(config(ConflictPolicy::Keep)).unwrap();
+        assert_eq!(column(&db, "cpu.a"), (vec![(1, 10, float(5.0))], vec![0]));
+        assert_eq!(column(&db, "cpu.b"), (vec![(0, 10, float(3.0))], vec![0]));
+    }
+
+    #[test]
+    fn columns_tell_measurements_with_dots_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = SolipsistDB::new(Config {
+            cwd: dir.path().to_owned(),
+            ..Config::default()
+        })
+        .unwrap();
+        // Both fields were once kept in a column named `cpu.a.b`.
+        let errors = db
+            .write("cpu.a b=1i 10\ncpu a.b=\"x\" 10\n../cpu c=true 10")
+            .unwrap();
+        assert!(errors.is_empty(), "{errors:?}");
+        db.flush().unwrap();
+        assert_eq!(
+            split_column_name(r"\.\./cpu.c"),
+            Some(("../cpu".to_owned(), "c"))
+        );
+
+        let fields = [("cpu.a", "b"), ("cpu", "a.b"), ("../cpu", "c")];
+        let values = [
+            ColumnValue::Integer(1),
+            ColumnValue::String("x".to_owned()),
+            ColumnValue::Boolean(true),
+        ];
+        for ((measurement, field), value) in fields.into_iter().zip(values) {
+            let result = db
+                .query(&format!(r#"SELECT * FROM "{measurement}""#))
+                .unwrap();
+            assert_eq!(result.columns, vec![field], "{measurement}");
+            assert_eq!(result.rows[0].values, vec![Some(value)], "{measurement}");
+        }
+    }
+
+    #[test]
+    fn write_overrides_sync_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = SolipsistDB::new(Config {
+            cwd: dir.path().to_owned(),
+            sync_policy: SyncPolicy::Interval(Duration::from_secs(3600)),
+            ..Config::default()
+        })
+        .unwrap();
+        db.write("cpu usage=1 1").unwrap();
+        let sync = db.inner.lock().unwrap().wal.sync_handle();
+        assert_eq!(sync.synced(), 0);
+
+        db.write_with("cpu usage=2 2", SyncPolicy::Always).unwrap();
+        assert_eq!(sync.synced(), 2);
+    }
+
+    #[test]
+    fn snapshots_see_whole_flushes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = SolipsistDB::new(Config {
+            cwd: dir.path().to_owned(),
+            ..Config::default()
+        })
+        .unwrap();
+        db.write("cpu,host=a usage=1 10\ncpu,host=b usage=2 10")
+            .unwrap();
+        db.flush().unwrap();
+        let snapshot = db.snapshot();
+
+        db.write("cpu,host=a usage=3 20\nmem,host=a free=4 20")
+            .unwrap();
+        db.flush().unwrap();
+        assert!(matches!(db.vacuum(), Err(Error::SnapshotsOpen(1))));
+        let points = |snapshot: &Snapshot| -> Vec<(String, i64)> {
+            snapshot
+                .points()
+                .map(|point| {
+                    let (series, timestamp, _) = point.unwrap();
+                    (series.to_string(), timestamp)
+                })
+                .collect()
+        };
+        assert_eq!(
+            points(&snapshot),
+            vec![("cpu,host=a".to_owned(), 10), ("cpu,host=b".to_owned(), 10)]
+        );
+        let (_, _, fields) = snapshot.points().next().unwrap().unwrap();
+        assert_eq!(fields["usage"], ColumnValue::Float(1.0));
+        drop(snapshot);
+
+        assert_eq!(points(&db.snapshot()).len(), 4);
+        db.vacuum().unwrap();
+    }
+
+    #[test]
+    fn flushed_databases_pass_the_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = SolipsistDB::new(Config {
+            cwd: dir.path().to_owned(),
+            ..Config::default()
+        })
+        .unwrap();
+        let lines: Vec<String> = (0..2000)
+            .map(|n| format!("cpu,host=h{} usage={n} {n}", n % 7))
+            .collect();
+        db.write(&lines.join("\n")).unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        let reports = check(dir.path()).unwrap();
+        assert_eq!(reports.len(), 2);
+        for (path, report) in &reports {
+            assert!(report.is_ok(), "{}: {:?}", path.display(), report.issues);
+        }
+        assert_eq!(reports[0].1.keys, 2000);
+        assert_eq!(reports[1].1.keys, 7);
+    }
+
+    #[test]
+    fn tag_queries_use_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = || Config {
+            cwd: dir.path().to_owned(),
+            ..Config::default()
+        };
+        let db = SolipsistDB::new(config()).unwrap();
+        db.write(
+            "temperature,location=office,floor=1 value=1 10\n\
+             temperature,location=office,floor=2 value=2 10\n\
+             temperature,location=garage,floor=1 value=3 10\n\
+             humidity,location=office value=4 10\n\
+             temperature value=5 10",
+        )
+        .unwrap();
+        db.flush().unwrap();
+        let series = |db: &SolipsistDB, query: &str| -> Vec<String> {
+            let result = db.query(query).unwrap();
+            result
+                .rows
+                .iter()
+                .map(|row| row.series.to_string())
+                .collect()
+        };
+        assert_eq!(
+            series(&db, "SELECT * FROM temperature WHERE location = 'office'"),
+            vec![
+                "temperature,floor=1,location=office",
+                "temperature,floor=2,location=office"
+            ]
+        );
+        assert_eq!(
+            series(
+                &db,
+                "SELECT * FROM temperature WHERE location = 'office' AND floor = '2'"
+            ),
+            vec!["temperature,floor=2,location=office"]
+        );
+        assert_eq!(series(&db, "SELECT * FROM temperature").len(), 4);
+        assert!(series(&db, "SELECT * FROM temperature WHERE location = 'attic'").is_empty());
+
+        // Queries only see what the index lists.
+        {
+            let inner = db.inner.lock().unwrap();
+            let garage = SeriesKey {
+                measurement: "temperature".to_owned(),
+                tags: vec![
+                    ("floor".to_owned(), "1".to_owned()),
+                    ("location".to_owned(), "garage".to_owned()),
+                ],
+            };
+            let id = inner.series_ids[&garage];
+            let key = tag_key("temperature", "location", "garage", id);
+            inner.tags.remove(&key).unwrap().unwrap();
+        }
+        assert!(series(&db, "SELECT * FROM temperature WHERE location = 'garage'").is_empty());
+        drop(db);
+
+        // A lost index is rebuilt from the catalog.
+        std::fs::remove_file(dir.path().join("series").join(TAGS)).unwrap();
+        let db = SolipsistDB::new(config()).unwrap();
+        assert_eq!(
+            series(&db, "SELECT * FROM temperature WHERE location = 'garage'"),
+            vec!["temperature,floor=1,location=garage"]
+        );
+        assert_eq!(
+            series(&db, "SELECT * FROM humidity WHERE location = 'office'"),
+            vec!["humidity,location=office"]
+        );
+    }
+
+    #[test]
+    fn point_keys_preserve_order() {
+        let keys = [
+            (0, i64::MIN),
+            (0, -1),
+            (0, 0),
+            (0, i64::MAX),
+            (1, i64::MIN),
+            (1, 1),
+            (256, -5),
+        ];
+        for pair in keys.windows(2) {
+            assert!(point_key(pair[0].0, pair[0].1) < point_key(pair[1].0, pair[1].1));
+        }
+        for (series, timestamp) in keys {
+            let key = point_key(series, timestamp);
+            assert_eq!(from_point_key(&key).unwrap(), (series, timestamp));
+        }
+    }
+}
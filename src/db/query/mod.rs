@@ -0,0 +1,276 @@
+use std::ops::Bound;
+
+use crate::errors::{Error, Result};
+
+/// A parsed `SELECT` statement:
+///
+/// `SELECT <field>[, <field>...] | * FROM <measurement> [WHERE <condition> [AND <condition>...]]`
+///
+/// where a condition is either `<tag> = '<value>'` or `time <op> <nanoseconds>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    /// Projected fields, empty for `*`.
+    pub fields: Vec<String>,
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub start: Bound<i64>,
+    pub end: Bound<i64>,
+}
+
+impl Query {
+    pub fn contains_time(&self, timestamp: i64) -> bool {
+        let after_start = match self.start {
+            Bound::Included(start) => timestamp >= start,
+            Bound::Excluded(start) => timestamp > start,
+            Bound::Unbounded => true,
+        };
+        let before_end = match self.end {
+            Bound::Included(end) => timestamp <= end,
+            Bound::Excluded(end) => timestamp < end,
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    /// First and last timestamp the query covers, `None` if it covers none.
+    pub fn time_range(&self) -> Option<(i64, i64)> {
+        let start = match self.start {
+            Bound::Included(start) => start,
+            Bound::Excluded(start) => start.checked_add(1)?,
+            Bound::Unbounded => i64::MIN,
+        };
+        let end = match self.end {
+            Bound::Included(end) => end,
+            Bound::Excluded(end) => end.checked_sub(1)?,
+            Bound::Unbounded => i64::MAX,
+        };
+        (start <= end).then_some((start, end))
+    }
+
+    pub fn matches_tags(&self, tags: &[(String, String)]) -> bool {
+        self.tags.iter().all(|filter| tags.contains(filter))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(i64),
+    Op(&'static str),
+    Comma,
+    Star,
+}
+
+pub fn parse(input: &str) -> Result<Query> {
+    let tokens = tokenize(input)?;
+    let mut tokens = tokens.into_iter().peekable();
+
+    expect_keyword(tokens.next(), "SELECT")?;
+    let mut fields = vec![];
+    if tokens.peek() == Some(&Token::Star) {
+        tokens.next();
+    } else {
+        loop {
+            fields.push(expect_ident(tokens.next())?);
+            if tokens.peek() != Some(&Token::Comma) {
+                break;
+            }
+            tokens.next();
+        }
+    }
+
+    expect_keyword(tokens.next(), "FROM")?;
+    let measurement = expect_ident(tokens.next())?;
+
+    let mut query = Query {
+        fields,
+        measurement,
+        tags: vec![],
+        start: Bound::Unbounded,
+        end: Bound::Unbounded,
+    };
+
+    match tokens.next() {
+        None => return Ok(query),
+        token => expect_keyword(token, "WHERE")?,
+    }
+    loop {
+        let key = expect_ident(tokens.next())?;
+        let op = match tokens.next() {
+            Some(Token::Op(op)) => op,
+            token => return Err(unexpected(token, "operator")),
+        };
+        if key == "time" {
+            let value = match tokens.next() {
+                Some(Token::Number(value)) => value,
+                token => return Err(unexpected(token, "timestamp")),
+            };
+            match op {
+                ">=" => query.start = Bound::Included(value),
+                ">" => query.start = Bound::Excluded(value),
+                "<=" => query.end = Bound::Included(value),
+                "<" => query.end = Bound::Excluded(value),
+                "=" => {
+                    query.start = Bound::Included(value);
+                    query.end = Bound::Included(value);
+                }
+                _ => {
+                    return Err(Error::InvalidQuery(format!(
+                        "unsupported time operator {op}"
+                    )))
+                }
+            }
+        } else {
+            if op != "=" {
+                return Err(Error::InvalidQuery(format!(
+                    "unsupported tag operator {op}"
+                )));
+            }
+            match tokens.next() {
+                Some(Token::Str(value)) => query.tags.push((key, value)),
+                token => return Err(unexpected(token, "string")),
+            }
+        }
+
+        match tokens.next() {
+            None => return Ok(query),
+            token => expect_keyword(token, "AND")?,
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            ',' => {
+                chars.next();
+                tokens.push(Token::Comma);
+            }
+            '*' => {
+                chars.next();
+                tokens.push(Token::Star);
+            }
+            '=' | '<' | '>' | '!' => {
+                chars.next();
+                let op = match (c, chars.peek()) {
+                    ('<', Some('=')) => "<=",
+                    ('>', Some('=')) => ">=",
+                    ('!', Some('=')) => "!=",
+                    ('<', _) => "<",
+                    ('>', _) => ">",
+                    ('=', _) => "=",
+                    _ => return Err(Error::InvalidQuery(format!("unexpected character {c}"))),
+                };
+                if op.len() == 2 {
+                    chars.next();
+                }
+                tokens.push(Token::Op(op));
+            }
+            '\'' | '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => value.extend(chars.next()),
+                        Some(q) if q == c => break,
+                        Some(other) => value.push(other),
+                        None => return Err(Error::InvalidQuery("unterminated quote".to_owned())),
+                    }
+                }
+                // Double quotes delimit identifiers, single quotes delimit strings.
+                tokens.push(match c {
+                    '"' => Token::Ident(value),
+                    _ => Token::Str(value),
+                });
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                let mut value = String::new();
+                value.push(c);
+                chars.next();
+                while let Some(&d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                    value.push(d);
+                    chars.next();
+                }
+                let number = value
+                    .parse()
+                    .map_err(|_| Error::InvalidQuery(format!("invalid number {value}")))?;
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut value = String::new();
+                while let Some(&d) = chars
+                    .peek()
+                    .filter(|d| d.is_alphanumeric() || matches!(d, '_' | '.' | '-'))
+                {
+                    value.push(d);
+                    chars.next();
+                }
+                tokens.push(Token::Ident(value));
+            }
+            _ => return Err(Error::InvalidQuery(format!("unexpected character {c}"))),
+        }
+    }
+    Ok(tokens)
+}
+
+fn expect_keyword(token: Option<Token>, keyword: &str) -> Result<()> {
+    match token {
+        Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword) => Ok(()),
+        token => Err(unexpected(token, keyword)),
+    }
+}
+
+fn expect_ident(token: Option<Token>) -> Result<String> {
+    match token {
+        Some(Token::Ident(ident)) => Ok(ident),
+        token => Err(unexpected(token, "identifier")),
+    }
+}
+
+fn unexpected(token: Option<Token>, expected: &str) -> Error {
+    match token {
+        Some(token) => Error::InvalidQuery(format!("expected {expected}, found {token:?}")),
+        None => Error::InvalidQuery(format!("expected {expected}, found end of query")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_select() {
+        let query = parse(
+            "SELECT temperature, humidity FROM weather WHERE location = 'office' AND time >= 10 AND time < 20",
+        )
+        .unwrap();
+        assert_eq!(
+            query,
+            Query {
+                fields: vec!["temperature".to_owned(), "humidity".to_owned()],
+                measurement: "weather".to_owned(),
+                tags: vec![("location".to_owned(), "office".to_owned())],
+                start: Bound::Included(10),
+                end: Bound::Excluded(20),
+            }
+        );
+        assert_eq!(query.time_range(), Some((10, 19)));
+        let query = parse("SELECT a FROM m WHERE time > 5 AND time < 6").unwrap();
+        assert_eq!(query.time_range(), None);
+    }
+
+    #[test]
+    fn parses_wildcard_and_quoted_identifiers() {
+        let query = parse(r#"select * from "my measurement""#).unwrap();
+        assert!(query.fields.is_empty());
+        assert_eq!(query.measurement, "my measurement");
+        assert!(parse("SELECT * FROM m WHERE location").is_err());
+        assert!(parse("SELECT * FROM m WHERE location = 'a").is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+use std::io;
+
+use crate::storage::format::UnsupportedVersion;
+use crate::storage::CorruptPage;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    InvalidQuery(String),
+    /// Arguments a storage operation cannot work with, such as unsorted bulk load input.
+    InvalidInput(String),
+    /// A key larger than a B-tree leaf cell can hold, in bytes.
+    KeyTooLarge(usize),
+    /// A value larger than an overflow chain can hold, in bytes.
+    ValueTooLarge(usize),
+    /// A page of B-tree storage whose content does not match its checksum.
+    CorruptPage(u32),
+    /// A storage operation that rewrites pages in place while snapshots still read them.
+    SnapshotsOpen(usize),
+    /// A storage file with a major format version newer than this build reads.
+    UnsupportedVersion {
+        major: u16,
+        minor: u16,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "io error: {err}"),
+            Error::InvalidQuery(reason) => write!(f, "invalid query: {reason}"),
+            Error::InvalidInput(reason) => write!(f, "invalid input: {reason}"),
+            Error::KeyTooLarge(len) => write!(f, "key of {len} bytes is too large"),
+            Error::ValueTooLarge(len) => write!(f, "value of {len} bytes is too large"),
+            Error::CorruptPage(page) => write!(f, "page {page} failed its checksum"),
+            Error::SnapshotsOpen(count) => write!(f, "{count} snapshots are still open"),
+            Error::UnsupportedVersion { major, minor } => {
+                write!(f, "unsupported storage format version {major}.{minor}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        let inner = err.get_ref();
+        if let Some(corrupt) = inner.and_then(|inner| inner.downcast_ref::<CorruptPage>()) {
+            return Error::CorruptPage(corrupt.page);
+        }
+        if let Some(UnsupportedVersion(version)) = inner.and_then(|inner| inner.downcast_ref()) {
+            return Error::UnsupportedVersion {
+                major: version.major,
+                minor: version.minor,
+            };
+        }
+        Error::Io(err)
+    }
+}
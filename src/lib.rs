@@ -0,0 +1,13 @@
+pub mod byte_encoder;
+pub mod clock;
+pub mod column_store;
+pub mod column_value;
+pub mod db;
+pub mod errors;
+pub mod line_protocol;
+pub mod storage;
+pub mod wal;
+
+pub use db::{Config, ConflictPolicy, QueryResult, Rejection, SolipsistDB};
+pub use errors::{Error, Result};
+pub use wal::SyncPolicy;
@@ -0,0 +1,457 @@
+use std::fmt;
+
+use crate::column_value::ColumnValue;
+
+/// A single point parsed from InfluxDB Line Protocol:
+///
+/// `measurement[,tag_key=tag_value...] field_key=field_value[,field_key=field_value...] [timestamp]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, ColumnValue)>,
+    /// Nanoseconds since the unix epoch, `None` when the line carries no timestamp.
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    MissingMeasurement,
+    MissingTagKey,
+    MissingTagValue,
+    MissingFields,
+    MissingFieldKey,
+    MissingFieldValue,
+    UnterminatedString,
+    InvalidFieldValue(String),
+    InvalidTimestamp(String),
+    UnexpectedCharacter(char),
+}
+
+/// A parse failure, positioned by 1-based line and column (in characters).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::MissingMeasurement => write!(f, "missing measurement"),
+            ParseErrorKind::MissingTagKey => write!(f, "missing tag key"),
+            ParseErrorKind::MissingTagValue => write!(f, "missing tag value"),
+            ParseErrorKind::MissingFields => write!(f, "missing fields"),
+            ParseErrorKind::MissingFieldKey => write!(f, "missing field key"),
+            ParseErrorKind::MissingFieldValue => write!(f, "missing field value"),
+            ParseErrorKind::UnterminatedString => write!(f, "unterminated string field value"),
+            ParseErrorKind::InvalidFieldValue(value) => write!(f, "invalid field value '{value}'"),
+            ParseErrorKind::InvalidTimestamp(value) => write!(f, "invalid timestamp '{value}'"),
+            ParseErrorKind::UnexpectedCharacter(c) => write!(f, "unexpected character '{c}'"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.kind
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", escape(&self.measurement, ", "))?;
+        for (key, value) in &self.tags {
+            write!(f, ",{}={}", escape(key, ",= "), escape(value, ",= "))?;
+        }
+        for (idx, (key, value)) in self.fields.iter().enumerate() {
+            let separator = if idx == 0 { ' ' } else { ',' };
+            write!(f, "{separator}{}=", escape(key, ",= "))?;
+            match value {
+                ColumnValue::Integer(value) => write!(f, "{value}i")?,
+                ColumnValue::Unsigned(value) => write!(f, "{value}u")?,
+                ColumnValue::Float(value) => write!(f, "{value}")?,
+                ColumnValue::Boolean(value) => write!(f, "{value}")?,
+                ColumnValue::String(value) => write!(f, "\"{}\"", escape(value, "\"\\"))?,
+                // Blobs and timestamps have no line protocol representation.
+                ColumnValue::Blob(_) | ColumnValue::Timestamp(_) => return Err(fmt::Error),
+            }
+        }
+        if let Some(timestamp) = self.timestamp {
+            write!(f, " {timestamp}")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn escape(value: &str, special: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Parses a batch of lines. Blank lines and `#` comments are skipped, every other
+/// line yields its own result so a bad line does not reject the whole batch.
+pub fn parse_lines(input: &str) -> impl Iterator<Item = Result<Line, ParseError>> + '_ {
+    parse_numbered_lines(input).map(|(_, line)| line)
+}
+
+/// Like `parse_lines`, along with the 1-based number of each line.
+pub(crate) fn parse_numbered_lines(
+    input: &str,
+) -> impl Iterator<Item = (usize, Result<Line, ParseError>)> + '_ {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.trim_start();
+            !line.is_empty() && !line.starts_with('#')
+        })
+        .map(|(idx, line)| (idx + 1, Parser::new(line, idx + 1).parse()))
+}
+
+/// Parses a single line.
+pub fn parse_line(input: &str) -> Result<Line, ParseError> {
+    Parser::new(input, 1).parse()
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str, line: usize) -> Self {
+        let input = input.strip_suffix('\r').unwrap_or(input);
+        Parser {
+            input,
+            pos: 0,
+            line,
+        }
+    }
+
+    fn parse(mut self) -> Result<Line, ParseError> {
+        while self.peek() == Some(b' ') || self.peek() == Some(b'\t') {
+            self.pos += 1;
+        }
+
+        let measurement = self.read_escaped(b", ", b", ");
+        if measurement.is_empty() {
+            return Err(self.error(ParseErrorKind::MissingMeasurement));
+        }
+
+        let mut tags = vec![];
+        while self.peek() == Some(b',') {
+            self.pos += 1;
+            tags.push(self.parse_tag()?);
+        }
+
+        if self.peek() != Some(b' ') {
+            return Err(self.error(ParseErrorKind::MissingFields));
+        }
+        self.pos += 1;
+
+        let mut fields = vec![self.parse_field()?];
+        while self.peek() == Some(b',') {
+            self.pos += 1;
+            fields.push(self.parse_field()?);
+        }
+
+        let timestamp = match self.peek() {
+            None => None,
+            Some(b' ') => {
+                self.pos += 1;
+                self.parse_timestamp()?
+            }
+            Some(c) => return Err(self.error(ParseErrorKind::UnexpectedCharacter(c as char))),
+        };
+
+        Ok(Line {
+            measurement,
+            tags,
+            fields,
+            timestamp,
+        })
+    }
+
+    fn parse_tag(&mut self) -> Result<(String, String), ParseError> {
+        let key = self.read_escaped(b",= ", b",= ");
+        if key.is_empty() {
+            return Err(self.error(ParseErrorKind::MissingTagKey));
+        }
+        if self.peek() != Some(b'=') {
+            return Err(self.error(ParseErrorKind::MissingTagValue));
+        }
+        self.pos += 1;
+
+        let value = self.read_escaped(b",= ", b",= ");
+        if value.is_empty() {
+            return Err(self.error(ParseErrorKind::MissingTagValue));
+        }
+        if self.peek() == Some(b'=') {
+            return Err(self.error(ParseErrorKind::UnexpectedCharacter('=')));
+        }
+        Ok((key, value))
+    }
+
+    fn parse_field(&mut self) -> Result<(String, ColumnValue), ParseError> {
+        let key = self.read_escaped(b",= ", b",= ");
+        if key.is_empty() {
+            return Err(self.error(ParseErrorKind::MissingFieldKey));
+        }
+        if self.peek() != Some(b'=') {
+            return Err(self.error(ParseErrorKind::MissingFieldValue));
+        }
+        self.pos += 1;
+
+        let value = match self.peek() {
+            Some(b'"') => self.parse_string()?,
+            _ => self.parse_scalar()?,
+        };
+        Ok((key, value))
+    }
+
+    fn parse_string(&mut self) -> Result<ColumnValue, ParseError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut value = Vec::new();
+        loop {
+            match self.peek() {
+                None => {
+                    self.pos = start;
+                    return Err(self.error(ParseErrorKind::UnterminatedString));
+                }
+                Some(b'"') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b'\\') if matches!(self.peek_at(1), Some(b'"' | b'\\')) => {
+                    value.push(self.input.as_bytes()[self.pos + 1]);
+                    self.pos += 2;
+                }
+                Some(c) => {
+                    value.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        // Only ASCII bytes are unescaped, so the result is still valid UTF-8.
+        Ok(ColumnValue::String(String::from_utf8(value).unwrap()))
+    }
+
+    fn parse_scalar(&mut self) -> Result<ColumnValue, ParseError> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c == b',' || c == b' ' {
+                break;
+            }
+            self.pos += 1;
+        }
+        let raw = &self.input[start..self.pos];
+        if raw.is_empty() {
+            return Err(self.error(ParseErrorKind::MissingFieldValue));
+        }
+
+        let invalid = |parser: &mut Self| {
+            parser.pos = start;
+            Err(parser.error(ParseErrorKind::InvalidFieldValue(raw.to_owned())))
+        };
+
+        if let Some(digits) = raw.strip_suffix('i') {
+            return match digits.parse::<i64>() {
+                Ok(value) => Ok(ColumnValue::Integer(value)),
+                Err(_) => invalid(self),
+            };
+        }
+        if let Some(digits) = raw.strip_suffix('u') {
+            return match digits.parse::<u64>() {
+                Ok(value) => Ok(ColumnValue::Unsigned(value)),
+                Err(_) => invalid(self),
+            };
+        }
+        match raw {
+            "t" | "T" | "true" | "True" | "TRUE" => return Ok(ColumnValue::Boolean(true)),
+            "f" | "F" | "false" | "False" | "FALSE" => return Ok(ColumnValue::Boolean(false)),
+            _ => {}
+        }
+
+        let is_numeric = raw
+            .bytes()
+            .all(|c| c.is_ascii_digit() || matches!(c, b'-' | b'+' | b'.' | b'e' | b'E'));
+        match raw.parse::<f64>() {
+            Ok(value) if is_numeric && value.is_finite() => Ok(ColumnValue::Float(value)),
+            _ => invalid(self),
+        }
+    }
+
+    fn parse_timestamp(&mut self) -> Result<Option<i64>, ParseError> {
+        let start = self.pos;
+        let raw = self.input[start..].trim_end();
+        if raw.is_empty() {
+            return Ok(None);
+        }
+        raw.parse::<i64>().map(Some).map_err(|_| {
+            self.pos = start;
+            self.error(ParseErrorKind::InvalidTimestamp(raw.to_owned()))
+        })
+    }
+
+    /// Reads up to the first unescaped byte in `terminators`. A backslash before
+    /// any byte in `escapable` yields that byte, any other backslash is literal.
+    fn read_escaped(&mut self, terminators: &[u8], escapable: &[u8]) -> String {
+        let mut value = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == b'\\' {
+                if let Some(next) = self.peek_at(1).filter(|next| escapable.contains(next)) {
+                    value.push(next);
+                    self.pos += 2;
+                    continue;
+                }
+            } else if terminators.contains(&c) {
+                break;
+            }
+            value.push(c);
+            self.pos += 1;
+        }
+        // Escapes and terminators are ASCII, so multi-byte characters are copied whole.
+        String::from_utf8(value).unwrap()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.input.as_bytes().get(self.pos + offset).copied()
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.input[..self.pos].chars().count() + 1,
+            kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_full_line() {
+        let line = parse_line(
+            "temperature,location=office,floor=2 value=72.5,count=3i,ok=true 1465839830100400200",
+        )
+        .unwrap();
+        assert_eq!(
+            line,
+            Line {
+                measurement: "temperature".to_owned(),
+                tags: vec![
+                    ("location".to_owned(), "office".to_owned()),
+                    ("floor".to_owned(), "2".to_owned()),
+                ],
+                fields: vec![
+                    ("value".to_owned(), ColumnValue::Float(72.5)),
+                    ("count".to_owned(), ColumnValue::Integer(3)),
+                    ("ok".to_owned(), ColumnValue::Boolean(true)),
+                ],
+                timestamp: Some(1465839830100400200),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_escapes_and_strings() {
+        let line = parse_line(
+            r#"my\ measurement,tag\,key=tag\=value msg="say \"hi\" \\ bye",path="C:\dir" -10"#,
+        )
+        .unwrap();
+        assert_eq!(line.measurement, "my measurement");
+        assert_eq!(
+            line.tags,
+            vec![("tag,key".to_owned(), "tag=value".to_owned())]
+        );
+        assert_eq!(
+            line.fields,
+            vec![
+                (
+                    "msg".to_owned(),
+                    ColumnValue::String(r#"say "hi" \ bye"#.to_owned())
+                ),
+                ("path".to_owned(), ColumnValue::String(r"C:\dir".to_owned())),
+            ]
+        );
+        assert_eq!(line.timestamp, Some(-10));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let input = r#"my\ m,t\=k=v\,1 f\ 1=-1.5,f2=2i,u=3u,b=false,s="a \"b\" \\" 5"#;
+        let line = parse_line(input).unwrap();
+        assert_eq!(line.to_string(), input);
+        assert_eq!(parse_line(&line.to_string()).unwrap(), line);
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        let results: Vec<_> = parse_lines(
+            "# comment\ncpu value=1\n\ncpu,host value=1\ncpu value=1x\ncpu value=\"open",
+        )
+        .collect();
+        assert_eq!(results.len(), 4);
+        assert!(results[0].is_ok());
+        assert_eq!(
+            results[1],
+            Err(ParseError {
+                line: 4,
+                column: 9,
+                kind: ParseErrorKind::MissingTagValue
+            })
+        );
+        assert_eq!(
+            results[2],
+            Err(ParseError {
+                line: 5,
+                column: 11,
+                kind: ParseErrorKind::InvalidFieldValue("1x".to_owned())
+            })
+        );
+        assert_eq!(
+            results[3],
+            Err(ParseError {
+                line: 6,
+                column: 11,
+                kind: ParseErrorKind::UnterminatedString
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_numbers() {
+        assert!(parse_line("cpu value=9223372036854775808i").is_err());
+        assert!(parse_line("cpu value=18446744073709551616u").is_err());
+        assert!(parse_line("cpu value=-1u").is_err());
+        assert!(parse_line("cpu value=1 12a").is_err());
+        assert_eq!(
+            parse_line("cpu value=18446744073709551615u")
+                .unwrap()
+                .fields,
+            vec![("value".to_owned(), ColumnValue::Unsigned(u64::MAX))]
+        );
+    }
+}
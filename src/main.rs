@@ -1,21 +1,9 @@
-use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
-use std::time::{Duration, SystemTime};
-
-use wal::WriteAheadLog;
-mod byte_encoder;
-pub mod clock;
-pub mod column_store;
-pub mod column_value;
-pub mod storage;
-pub mod wal;
-
-pub struct TimeSeriesDatabase {
-    data: BTreeMap<SystemTime, f64>,
-    wal: WriteAheadLog,
-}
+use std::process::ExitCode;
 
 use clap::{Parser, Subcommand};
+use serde::Serialize;
+use solipsist_db::storage::check::{check_file, Report};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -43,8 +31,51 @@ enum Commands {
         #[arg(short, long)]
         list: bool,
     },
+    /// checks B-tree files for corruption and prints a JSON report
+    Check {
+        /// a database directory or a single B-tree file
+        path: PathBuf,
+    },
+}
+
+/// Report of one file in the output of `check`.
+#[derive(Serialize)]
+struct FileReport {
+    file: PathBuf,
+    #[serde(flatten)]
+    report: Report,
+}
+
+/// Checks the files at `path`, exiting with 1 when any has issues and 2 when one cannot
+/// be checked at all.
+fn check(path: PathBuf) -> ExitCode {
+    let reports = if path.is_dir() {
+        solipsist_db::db::check(&path)
+    } else {
+        check_file(&path).map(|report| vec![(path, report)])
+    };
+    let reports: Vec<FileReport> = match reports {
+        Ok(reports) => reports
+            .into_iter()
+            .map(|(file, report)| FileReport { file, report })
+            .collect(),
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::from(2);
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&reports).unwrap());
+    if reports.iter().all(|file| file.report.is_ok()) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(1)
+    }
 }
 
-fn main() {
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    match cli.command {
+        Some(Commands::Check { path }) => check(path),
+        Some(Commands::Test { .. }) | None => ExitCode::SUCCESS,
+    }
 }
@@ -1,104 +1,392 @@
-use std::cmp::Ordering;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::io::{BufReader, Read, Result};
+use std::io::{self, Cursor, Read};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
 
 use crate::byte_encoder::{ByteDecoder, ByteEncoder};
+use crate::errors::{Error, Result};
+
+use super::invalid_data;
+use super::latch::{Latch, Latches, Writes, ROOT_LATCH};
+use super::pager::{FileId, Pager};
+use super::paging::{Page, PageHeader, OVERFLOW_CAPACITY, PAGE_HEADER_SIZE, USABLE_SIZE};
+
+/// Bytes a leaf cell takes besides its key and value: their lengths and the cell pointer.
+const LEAF_CELL_OVERHEAD: usize = 2 + 4 + 2;
 
-const ORDER: usize = 4;
+/// Bytes an interior cell takes besides its key: left child, key length and cell pointer.
+const INTERIOR_CELL_OVERHEAD: usize = 4 + 2 + 2;
 
+/// Largest key and value stored together in a leaf cell, larger values spill into
+/// overflow pages. Keeping cells under a quarter of the page means both halves of a
+/// split node always fit in their pages.
+pub const MAX_PAIR_SIZE: usize = (USABLE_SIZE - PAGE_HEADER_SIZE) / 4 - LEAF_CELL_OVERHEAD;
+
+/// Largest key, the cell of a spilled value holds the key and the first overflow page.
+pub const MAX_KEY_SIZE: usize = MAX_PAIR_SIZE - 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BTreePageType {
     InteriorTable,
     LeafTable,
     InteriorIndex,
     LeafIndex,
+    /// Part of a value too large for a leaf cell, see `LeafValue::Overflow`.
+    Overflow,
+    /// A free page holding part of the free list.
+    FreeList,
+}
+
+impl TryFrom<u8> for BTreePageType {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> io::Result<Self> {
+        match value {
+            0x02 => Ok(BTreePageType::InteriorIndex),
+            0x05 => Ok(BTreePageType::InteriorTable),
+            0x0a => Ok(BTreePageType::LeafIndex),
+            0x0d => Ok(BTreePageType::LeafTable),
+            0x10 => Ok(BTreePageType::Overflow),
+            0x20 => Ok(BTreePageType::FreeList),
+            _ => Err(invalid_data(format!("unknown page type {value:#04x}"))),
+        }
+    }
 }
 
-impl From<u8> for BTreePageType {
-    fn from(value: u8) -> Self {
+impl From<BTreePageType> for u8 {
+    fn from(value: BTreePageType) -> Self {
         match value {
-            0x02 => BTreePageType::InteriorIndex,
-            0x05 => BTreePageType::InteriorTable,
-            0x0a => BTreePageType::LeafIndex,
-            0x0d => BTreePageType::LeafTable,
-            _ => unimplemented!(),
+            BTreePageType::InteriorIndex => 0x02,
+            BTreePageType::InteriorTable => 0x05,
+            BTreePageType::LeafIndex => 0x0a,
+            BTreePageType::LeafTable => 0x0d,
+            BTreePageType::Overflow => 0x10,
+            BTreePageType::FreeList => 0x20,
         }
     }
 }
 
-#[derive(Debug)]
-pub struct KeysIterator<'a> {
-    results: VecDeque<u64>,
-    queue: VecDeque<&'a Node>,
+/// What a tree stores, which decides the types of its pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TreeKind {
+    /// Keys with values, in `LeafTable` and `InteriorTable` pages.
+    Table,
+    /// Keys alone, in `LeafIndex` and `InteriorIndex` pages. Whatever an index maps to
+    /// is part of its keys.
+    Index,
 }
 
-impl<'a> Default for KeysIterator<'a> {
-    fn default() -> Self {
-        KeysIterator {
-            results: VecDeque::new(),
-            queue: VecDeque::new(),
+impl TreeKind {
+    pub(super) fn leaf_type(self) -> BTreePageType {
+        match self {
+            TreeKind::Table => BTreePageType::LeafTable,
+            TreeKind::Index => BTreePageType::LeafIndex,
+        }
+    }
+
+    pub(super) fn interior_type(self) -> BTreePageType {
+        match self {
+            TreeKind::Table => BTreePageType::InteriorTable,
+            TreeKind::Index => BTreePageType::InteriorIndex,
         }
     }
 }
 
-impl<'a> Iterator for KeysIterator<'a> {
-    type Item = u64;
+/// Where the front of a `Range` continues once its buffered pairs run out, `Above(key)`
+/// descends to the leaf holding the keys after `key`.
+enum Next {
+    Start,
+    Above(Vec<u8>),
+    End,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if !self.results.is_empty() {
-            return self.results.pop_front();
+/// Where the back of a `Range` continues, `Below(key)` descends to the leaf holding the
+/// keys up to `key`.
+enum Prev {
+    Start,
+    Below(Vec<u8>),
+    End,
+}
+
+/// Pairs of a tree within a key range, in order. Leaves do not link to their siblings
+/// through `right_pointer`, which copy-on-write updates cannot keep up, see `Node`.
+/// Each leaf after the first is instead reached from the interior nodes read on the
+/// way to the one before it, and `.rev()` walks them the same way backwards. Once a
+/// writer changed the tree in between, the leaf is found by descending from the root
+/// past the separator that bounds the leaf before it. A range over the live tree sees
+/// every leaf as the writers left it by the time the range gets there, so keys
+/// inserted or removed during the scan may or may not show up. Only a range taken
+/// from a `snapshot` reads one version of the tree throughout.
+pub struct Range<'a> {
+    tree: View<'a>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    front: VecDeque<(Vec<u8>, LeafValue)>,
+    next: Next,
+    /// The way to the last leaf loaded into `front`.
+    front_path: Option<Trail>,
+    back: VecDeque<(Vec<u8>, LeafValue)>,
+    prev: Prev,
+    back_path: Option<Trail>,
+    /// Last keys yielded from either end, the two ends stop when they meet.
+    front_key: Option<Vec<u8>>,
+    back_key: Option<Vec<u8>>,
+}
+
+impl Range<'_> {
+    fn finish(&mut self) {
+        self.front.clear();
+        self.back.clear();
+        self.next = Next::End;
+        self.prev = Prev::End;
+    }
+
+    /// Loads the next leaf into `front`, false once there are none left.
+    fn load_front(&mut self) -> Result<bool> {
+        let (node, path) = match &self.next {
+            Next::Start => match &self.start {
+                Bound::Included(key) | Bound::Excluded(key) => self.tree.descend(Some(key))?,
+                Bound::Unbounded => self.tree.descend(Some(&[]))?,
+            },
+            Next::Above(key) => match self.tree.walk(self.front_path.take(), Step::Next)? {
+                Some(walked) => walked,
+                None => {
+                    // The smallest key after `key` is `key` with a zero byte appended.
+                    let mut after = key.clone();
+                    after.push(0);
+                    self.tree.descend(Some(&after))?
+                }
+            },
+            Next::End => return Ok(false),
+        };
+        let Node::Leaf { keys, values } = node else {
+            unreachable!("descend stops at a leaf");
+        };
+        self.next = match path.upper() {
+            Some(key) => Next::Above(key.clone()),
+            None => Next::End,
+        };
+        self.front_path = Some(path);
+        self.front.extend(keys.into_iter().zip(values));
+        Ok(true)
+    }
+
+    /// Loads the previous leaf into `back`, false once there are none left.
+    fn load_back(&mut self) -> Result<bool> {
+        let (node, path) = match &self.prev {
+            Prev::Start => match &self.end {
+                Bound::Included(key) | Bound::Excluded(key) => self.tree.descend(Some(key))?,
+                Bound::Unbounded => self.tree.descend(None)?,
+            },
+            Prev::Below(key) => match self.tree.walk(self.back_path.take(), Step::Prev)? {
+                Some(walked) => walked,
+                None => self.tree.descend(Some(key))?,
+            },
+            Prev::End => return Ok(false),
+        };
+        let Node::Leaf { keys, values, .. } = node else {
+            unreachable!("descend stops at a leaf");
+        };
+        self.prev = match path.lower() {
+            Some(key) => Prev::Below(key.clone()),
+            None => Prev::End,
+        };
+        self.back_path = Some(path);
+        self.back.extend(keys.into_iter().zip(values));
+        Ok(true)
+    }
+
+    /// Reads a value about to be yielded, ending the range if its overflow chain fails.
+    fn resolve(&mut self, key: Vec<u8>, value: LeafValue) -> Result<KeyValuePair> {
+        match self.tree.read_value(value) {
+            Ok(value) => Ok(KeyValuePair::new(key, value)),
+            Err(err) => {
+                self.finish();
+                Err(err)
+            }
         }
-        loop {
-            if let Some(node) = self.queue.pop_front() {
-                match &node {
-                    Node::Leaf { values, .. } => {
-                        for value in values.iter() {
-                            self.results.push_back(value.key);
-                        }
+    }
+}
 
-                        if !values.is_empty() {
-                            break;
-                        }
+impl Iterator for Range<'_> {
+    type Item = Result<KeyValuePair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some((key, value)) = self.front.pop_front() else {
+                match self.load_front() {
+                    Ok(true) => continue,
+                    Ok(false) => return None,
+                    Err(err) => {
+                        self.finish();
+                        return Some(Err(err));
                     }
-                    Node::Internal { children, .. } => {
-                        for child in children.iter() {
-                            self.queue.push_back(child);
-                        }
+                }
+            };
+            if !self.start.contains_below(&key) {
+                continue;
+            }
+            let met = self.back_key.as_ref().is_some_and(|back| key >= *back);
+            if met || !self.end.contains_above(&key) {
+                self.finish();
+                return None;
+            }
+            self.front_key = Some(key.clone());
+            return Some(self.resolve(key, value));
+        }
+    }
+}
+
+impl DoubleEndedIterator for Range<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some((key, value)) = self.back.pop_back() else {
+                match self.load_back() {
+                    Ok(true) => continue,
+                    Ok(false) => return None,
+                    Err(err) => {
+                        self.finish();
+                        return Some(Err(err));
                     }
                 }
-            } else {
-                break;
+            };
+            if !self.end.contains_above(&key) {
+                continue;
             }
+            let met = self.front_key.as_ref().is_some_and(|front| key <= *front);
+            if met || !self.start.contains_below(&key) {
+                self.finish();
+                return None;
+            }
+            self.back_key = Some(key.clone());
+            return Some(self.resolve(key, value));
         }
-        if !self.results.is_empty() {
-            return self.results.pop_front();
+    }
+}
+
+trait BoundExt {
+    /// Whether `key` is not below a start bound.
+    fn contains_below(&self, key: &[u8]) -> bool;
+    /// Whether `key` is not above an end bound.
+    fn contains_above(&self, key: &[u8]) -> bool;
+}
+
+impl BoundExt for Bound<Vec<u8>> {
+    fn contains_below(&self, key: &[u8]) -> bool {
+        match self {
+            Bound::Included(start) => key >= start.as_slice(),
+            Bound::Excluded(start) => key > start.as_slice(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn contains_above(&self, key: &[u8]) -> bool {
+        match self {
+            Bound::Included(end) => key <= end.as_slice(),
+            Bound::Excluded(end) => key < end.as_slice(),
+            Bound::Unbounded => true,
         }
-        None
     }
 }
 
+/// Reads a stored value, loading one overflow page at a time for spilled values.
+pub struct ValueReader<'a> {
+    tree: View<'a>,
+    /// The inline value or the part of the current overflow page not read yet.
+    chunk: Vec<u8>,
+    pos: usize,
+    /// Next page of the overflow chain and the bytes left to read from the chain.
+    next: u32,
+    remaining: usize,
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            let page = self.tree.pager.get_page(self.tree.file, self.next)?;
+            let len = self.remaining.min(OVERFLOW_CAPACITY);
+            self.next = overflow_header(&page)?.right_pointer;
+            self.chunk = page.payload()[..len].to_vec();
+            self.pos = 0;
+            self.remaining -= len;
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Header of a page expected to be part of an overflow chain.
+fn overflow_header(page: &Page) -> io::Result<PageHeader> {
+    let header = page.header()?;
+    if header.page_type != BTreePageType::Overflow {
+        let page_type = header.page_type;
+        return Err(invalid_data(format!(
+            "expected an overflow page at {}, found {page_type:?}",
+            page.number
+        )));
+    }
+    Ok(header)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct KeyValuePair {
-    pub key: u64,
+    pub key: Vec<u8>,
     pub value: Vec<u8>,
 }
 
 impl KeyValuePair {
-    pub fn new(key: u64, value: Vec<u8>) -> Self {
+    pub fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
         KeyValuePair { key, value }
     }
 }
 
+/// A value as stored in a leaf.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LeafValue {
+    /// A value that fits in the leaf cell next to its key.
+    Inline(Vec<u8>),
+    /// A value of `len` bytes spilled into a chain of overflow pages starting at `first`,
+    /// used when the key and value together exceed `MAX_PAIR_SIZE`.
+    Overflow { len: u32, first: u32 },
+}
+
+impl LeafValue {
+    /// Bytes the value takes in its cell after the value length.
+    fn cell_len(&self) -> usize {
+        match self {
+            LeafValue::Inline(value) => value.len(),
+            LeafValue::Overflow { .. } => 4,
+        }
+    }
+}
+
+/// A B+tree node as read from its page. Keys are byte strings compared bytewise, the
+/// `write_ordered_*` methods of `ByteEncoder` build them from typed values. Interior
+/// nodes refer to their children by page number, `children[i]` holds the keys up to and
+/// including `keys[i]`. Leaves do not link to their siblings, since a copy-on-write
+/// update would then have to copy the leaf before every leaf it copies, and with it
+/// every leaf before that one. Range scans move between leaves through the interior
+/// nodes above them instead, see `Range`.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Node {
     Internal {
-        keys: Vec<u64>,
-        children: Vec<Node>,
+        keys: Vec<Vec<u8>>,
+        children: Vec<u32>,
     },
 
     Leaf {
-        keys: Vec<u64>,
-        values: Vec<KeyValuePair>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<LeafValue>,
     },
 }
 
@@ -110,30 +398,44 @@ impl Node {
         }
     }
 
-    fn new_internal() -> Self {
-        Node::Internal {
-            keys: vec![],
-            children: vec![],
+    /// Sizes of the cells the node is laid out in.
+    fn cell_sizes(&self) -> Vec<usize> {
+        match self {
+            Node::Leaf { keys, values, .. } => keys
+                .iter()
+                .zip(values)
+                .map(|(key, value)| LEAF_CELL_OVERHEAD + key.len() + value.cell_len())
+                .collect(),
+            Node::Internal { keys, .. } => keys
+                .iter()
+                .map(|key| INTERIOR_CELL_OVERHEAD + key.len())
+                .collect(),
         }
     }
 
+    /// Size of the node once laid out in a page.
+    fn size(&self) -> usize {
+        PAGE_HEADER_SIZE + self.cell_sizes().iter().sum::<usize>()
+    }
+
     pub fn is_full(&self) -> bool {
-        match self {
-            Node::Leaf { keys, .. } => keys.len() > 2 * ORDER - 1,
-            Node::Internal { children, .. } => children.len() > 2 * ORDER,
-        }
+        self.size() > USABLE_SIZE
     }
 
-    // Split a node into two new nodes when it has more than `ORDER` keys
-    fn split(&mut self) -> (Node, u64, Node) {
-        let mid = match self {
-            Node::Leaf { values, .. } => values.len() / 2 + 1,
-            Node::Internal { keys, .. } => keys.len() / 2,
-        };
+    /// Splits a node into two halves and the separator key to store in the parent.
+    /// Nodes split at half of their bytes, since keys and values vary in size.
+    fn split(&mut self) -> (Node, Vec<u8>, Node) {
+        let sizes = self.cell_sizes();
+        let total: usize = sizes.iter().sum();
         match self {
             Node::Leaf { keys, values } => {
-                let (keys, values) = (keys.clone(), values.clone());
-                println!("SPLITTING AT {mid} | {keys:?} | {values:?}");
+                let mut filled = 0;
+                let mut mid = 0;
+                while mid < values.len() && filled < total / 2 {
+                    filled += sizes[mid];
+                    mid += 1;
+                }
+                let mid = mid.clamp(1, values.len() - 1);
                 let left = Node::Leaf {
                     keys: keys[..mid].to_vec(),
                     values: values[..mid].to_vec(),
@@ -142,207 +444,1293 @@ impl Node {
                     keys: keys[mid..].to_vec(),
                     values: values[mid..].to_vec(),
                 };
-                (left, keys[mid - 1], right)
+                (left, keys[mid - 1].clone(), right)
+            }
+            Node::Internal { keys, children } => {
+                // The key at `mid` moves up to the parent.
+                let mut filled = 0;
+                let mut mid = 0;
+                while mid < keys.len() && filled + sizes[mid] <= total / 2 {
+                    filled += sizes[mid];
+                    mid += 1;
+                }
+                let mid = mid.clamp(1, keys.len() - 2);
+                let left = Node::Internal {
+                    keys: keys[..mid].to_vec(),
+                    children: children[..mid + 1].to_vec(),
+                };
+                let right = Node::Internal {
+                    keys: keys[mid + 1..].to_vec(),
+                    children: children[mid + 1..].to_vec(),
+                };
+                (left, keys[mid].clone(), right)
+            }
+        }
+    }
+
+    pub(super) fn to_page(&self, number: u32, kind: TreeKind) -> io::Result<Page> {
+        match self {
+            Node::Leaf { keys, .. } if kind == TreeKind::Index => {
+                let mut cells = Vec::with_capacity(keys.len());
+                for key in keys {
+                    let mut cell = ByteEncoder::new(Vec::with_capacity(2 + key.len()));
+                    cell.write_u16(key.len() as u16)?;
+                    cell.write_bytes(key)?;
+                    cells.push(cell.inner);
+                }
+                Page::from_cells(number, kind.leaf_type(), 0, &cells)
+            }
+            Node::Leaf { keys, values } => {
+                let mut cells = Vec::with_capacity(values.len());
+                for (key, value) in keys.iter().zip(values) {
+                    let len = 6 + key.len() + value.cell_len();
+                    let mut cell = ByteEncoder::new(Vec::with_capacity(len));
+                    cell.write_u16(key.len() as u16)?;
+                    cell.write_bytes(key)?;
+                    match value {
+                        LeafValue::Inline(value) => {
+                            cell.write_u32(value.len() as u32)?;
+                            cell.write_bytes(value)?;
+                        }
+                        LeafValue::Overflow { len, first } => {
+                            cell.write_u32(*len)?;
+                            cell.write_u32(*first)?;
+                        }
+                    }
+                    cells.push(cell.inner);
+                }
+                Page::from_cells(number, kind.leaf_type(), 0, &cells)
+            }
+            Node::Internal { keys, children } => {
+                let mut cells = Vec::with_capacity(keys.len());
+                for (key, child) in keys.iter().zip(children) {
+                    let mut cell = ByteEncoder::new(Vec::with_capacity(6 + key.len()));
+                    cell.write_u32(*child)?;
+                    cell.write_u16(key.len() as u16)?;
+                    cell.write_bytes(key)?;
+                    cells.push(cell.inner);
+                }
+                let right_pointer = *children.last().unwrap();
+                Page::from_cells(number, kind.interior_type(), right_pointer, &cells)
+            }
+        }
+    }
+
+    pub(super) fn from_page(page: &Page, kind: TreeKind) -> io::Result<Node> {
+        let header = page.header()?;
+        let truncated = |_| invalid_data(format!("truncated cell on page {}", page.number));
+        match header.page_type {
+            page_type if page_type == kind.leaf_type() && kind == TreeKind::Index => {
+                let mut keys = vec![];
+                for cell in page.cells()? {
+                    let mut reader = ByteDecoder::new(Cursor::new(cell));
+                    let len = reader.read_u16().map_err(truncated)? as usize;
+                    keys.push(reader.read_bytes(len).map_err(truncated)?);
+                }
+                let values = vec![LeafValue::Inline(vec![]); keys.len()];
+                Ok(Node::Leaf { keys, values })
+            }
+            page_type if page_type == kind.leaf_type() => {
+                let mut keys = vec![];
+                let mut values = vec![];
+                for cell in page.cells()? {
+                    let mut reader = ByteDecoder::new(Cursor::new(cell));
+                    let len = reader.read_u16().map_err(truncated)? as usize;
+                    let key = reader.read_bytes(len).map_err(truncated)?;
+                    let len = reader.read_u32().map_err(truncated)?;
+                    // Whether a value spilled follows from its size, like when it was stored.
+                    let value = if key.len() + len as usize > MAX_PAIR_SIZE {
+                        let first = reader.read_u32().map_err(truncated)?;
+                        LeafValue::Overflow { len, first }
+                    } else {
+                        LeafValue::Inline(reader.read_bytes(len as usize).map_err(truncated)?)
+                    };
+                    keys.push(key);
+                    values.push(value);
+                }
+                Ok(Node::Leaf { keys, values })
+            }
+            page_type if page_type == kind.interior_type() => {
+                let mut keys = vec![];
+                let mut children = vec![];
+                for cell in page.cells()? {
+                    let mut reader = ByteDecoder::new(Cursor::new(cell));
+                    children.push(reader.read_u32().map_err(truncated)?);
+                    let len = reader.read_u16().map_err(truncated)? as usize;
+                    keys.push(reader.read_bytes(len).map_err(truncated)?);
+                }
+                children.push(header.right_pointer);
+                Ok(Node::Internal { keys, children })
+            }
+            page_type => Err(invalid_data(format!(
+                "expected a {kind:?} page at {}, found {page_type:?}",
+                page.number
+            ))),
+        }
+    }
+
+    /// Removes a key from a leaf and returns its value.
+    fn remove(&mut self, key: &[u8]) -> Option<LeafValue> {
+        match self {
+            Node::Leaf { keys, values, .. } => {
+                let pos = keys
+                    .binary_search_by(|probe| probe.as_slice().cmp(key))
+                    .ok()?;
+                keys.remove(pos);
+                Some(values.remove(pos))
+            }
+            Node::Internal { .. } => unreachable!("values are only removed from leaves"),
+        }
+    }
+
+    /// A node less than a quarter full is merged with or borrows from a sibling.
+    pub(super) fn is_underflow(&self) -> bool {
+        self.size() < USABLE_SIZE / 4
+    }
+
+    /// Whether the node takes `change` below it without its parent having to change: an
+    /// insert cannot split it and a removal cannot leave it underfull.
+    fn absorbs(&self, change: Change) -> bool {
+        let largest_cell = match self {
+            Node::Leaf { .. } => LEAF_CELL_OVERHEAD + MAX_PAIR_SIZE,
+            Node::Internal { .. } => INTERIOR_CELL_OVERHEAD + MAX_KEY_SIZE,
+        };
+        match change {
+            Change::Insert => self.size() + largest_cell <= USABLE_SIZE,
+            Change::Remove => self.size() >= USABLE_SIZE / 4 + largest_cell,
+        }
+    }
+
+    /// Concatenates this node with its right sibling. Interior nodes pull the separator
+    /// down from the parent between their keys.
+    fn merge(self, separator: Vec<u8>, right: Node) -> Node {
+        match (self, right) {
+            (
+                Node::Leaf {
+                    mut keys,
+                    mut values,
+                },
+                Node::Leaf {
+                    keys: right_keys,
+                    values: right_values,
+                },
+            ) => {
+                keys.extend(right_keys);
+                values.extend(right_values);
+                Node::Leaf { keys, values }
+            }
+            (
+                Node::Internal {
+                    mut keys,
+                    mut children,
+                },
+                Node::Internal {
+                    keys: right_keys,
+                    children: right_children,
+                },
+            ) => {
+                keys.push(separator);
+                keys.extend(right_keys);
+                children.extend(right_children);
+                Node::Internal { keys, children }
+            }
+            _ => unreachable!("siblings are at the same depth"),
+        }
+    }
+}
+
+/// Combines a stored value with a new one for `ConflictPolicy::Merge`.
+pub type MergeFn<'a> = dyn Fn(&[u8], &[u8]) -> Vec<u8> + 'a;
+
+/// Separator key and page of the new right half of a split node.
+type Split = (Vec<u8>, u32);
+
+/// A leaf and the way to it, see `View::descend`.
+type Descent = (Node, Trail);
+
+/// An interior node on the way down to a leaf and the child taken there.
+struct Level {
+    keys: Vec<Vec<u8>>,
+    children: Vec<u32>,
+    idx: usize,
+}
+
+/// The interior nodes from the root down to a leaf, read while the tree stood at
+/// `writes`, see `Writes::quiet`.
+struct Trail {
+    levels: Vec<Level>,
+    writes: Option<u64>,
+}
+
+impl Trail {
+    /// The separator closest below the leaf, which routes to the previous leaf.
+    fn lower(&self) -> Option<&Vec<u8>> {
+        let level = self.levels.iter().rev().find(|level| level.idx > 0)?;
+        Some(&level.keys[level.idx - 1])
+    }
+
+    /// The separator closest above the leaf, the next leaf holds the keys after it.
+    fn upper(&self) -> Option<&Vec<u8>> {
+        let level = self
+            .levels
+            .iter()
+            .rev()
+            .find(|level| level.idx < level.keys.len())?;
+        Some(&level.keys[level.idx])
+    }
+}
+
+/// The direction `View::walk` moves in.
+#[derive(Clone, Copy)]
+enum Step {
+    Next,
+    Prev,
+}
+
+/// A change on its way down the tree, see `Node::absorbs`.
+#[derive(Clone, Copy)]
+enum Change {
+    Insert,
+    Remove,
+}
+
+/// Index of the child of an interior node that `key` routes to.
+fn route(keys: &[Vec<u8>], key: &[u8]) -> usize {
+    keys.binary_search_by(|probe| probe.as_slice().cmp(key))
+        .unwrap_or_else(|x| x)
+}
+
+/// How `Btree::upsert` resolves a key that is already stored.
+#[derive(Clone, Copy)]
+pub enum ConflictPolicy<'a> {
+    /// Replace the stored value with the new one.
+    Overwrite,
+    /// Leave the stored value in place and drop the new one.
+    Keep,
+    /// Store the result of combining the stored value with the new one.
+    Merge(&'a MergeFn<'a>),
+}
+
+/// Versions of a tree that snapshots may still read, shared by the tree and its
+/// snapshots.
+struct Versions {
+    /// Number and root of the version the last commit published.
+    epoch: u64,
+    root: u32,
+    /// Open snapshots by the version they read.
+    readers: BTreeMap<u64, usize>,
+    /// Pages that left the tree when the version after the one they are listed with was
+    /// published. They are freed once no snapshot reads that version or an older one,
+    /// until then the file lists them as free for after a restart.
+    retired: VecDeque<(u64, Vec<u32>)>,
+}
+
+impl Versions {
+    fn reclaim(&mut self, pager: &Pager, file: FileId) {
+        let oldest = self.readers.keys().next().copied().unwrap_or(self.epoch);
+        while self
+            .retired
+            .front()
+            .is_some_and(|(epoch, _)| *epoch < oldest)
+        {
+            for page in self.retired.pop_front().unwrap().1 {
+                pager.free_page(file, page);
+            }
+        }
+    }
+}
+
+/// Reads one version of a tree: the working version of `tree`, latching its nodes on
+/// the way down, or for a snapshot the committed version rooted at `root`.
+#[derive(Clone, Copy)]
+struct View<'a> {
+    pager: &'a Pager,
+    file: FileId,
+    kind: TreeKind,
+    root: u32,
+    tree: Option<&'a Btree>,
+}
+
+impl<'a> View<'a> {
+    fn search(self, key: &[u8]) -> Result<Option<KeyValuePair>> {
+        let (node, _) = self.descend(Some(key))?;
+        let Node::Leaf { keys, mut values } = node else {
+            unreachable!("descend stops at a leaf");
+        };
+        match keys.binary_search_by(|probe| probe.as_slice().cmp(key)) {
+            Ok(idx) => {
+                let value = self.read_value(values.swap_remove(idx))?;
+                Ok(Some(KeyValuePair::new(key.to_vec(), value)))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn value_reader(self, key: &[u8]) -> Result<Option<ValueReader<'a>>> {
+        let (node, _) = self.descend(Some(key))?;
+        let Node::Leaf { keys, mut values } = node else {
+            unreachable!("descend stops at a leaf");
+        };
+        Ok(keys
+            .binary_search_by(|probe| probe.as_slice().cmp(key))
+            .ok()
+            .map(|idx| self.open_value(values.swap_remove(idx))))
+    }
+
+    fn range<R: RangeBounds<Vec<u8>>>(self, range: R) -> Range<'a> {
+        Range {
+            tree: self,
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            front: VecDeque::new(),
+            next: Next::Start,
+            front_path: None,
+            back: VecDeque::new(),
+            prev: Prev::Start,
+            back_path: None,
+            front_key: None,
+            back_key: None,
+        }
+    }
+
+    fn prefix(self, prefix: &[u8]) -> Range<'a> {
+        // Keys with the prefix sort before the prefix with its last byte below 0xff
+        // incremented, a prefix of only 0xff bytes runs to the end of the tree.
+        let mut end = prefix.to_vec();
+        while end.last() == Some(&0xff) {
+            end.pop();
+        }
+        let end = match end.last_mut() {
+            Some(last) => {
+                *last += 1;
+                Bound::Excluded(end)
+            }
+            None => Bound::Unbounded,
+        };
+        self.range((Bound::Included(prefix.to_vec()), end))
+    }
+
+    fn keys(self) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + 'a {
+        self.range(..).map(|pair| pair.map(|pair| pair.key))
+    }
+
+    /// Reads the leaf `key` routes to, or the last leaf for `None`, along with the
+    /// interior nodes on the way down.
+    fn descend(self, key: Option<&[u8]>) -> Result<Descent> {
+        let latches = self.tree.map(|tree| &tree.latches);
+        let writes = self.quiet();
+        // Held for as long as the node below it is read.
+        let mut _latch = latches.map(|latches| latches.shared(ROOT_LATCH));
+        let mut page = match self.tree {
+            Some(tree) => tree.working.lock().unwrap().root,
+            None => self.root,
+        };
+        let mut levels = vec![];
+        loop {
+            // The child is latched before its parent is let go.
+            let child = latches.map(|latches| latches.shared(page));
+            _latch = child;
+            match self.read_node(page)? {
+                Node::Internal { keys, children } => {
+                    let idx = match key {
+                        Some(key) => route(&keys, key),
+                        None => keys.len(),
+                    };
+                    page = children[idx];
+                    levels.push(Level {
+                        keys,
+                        children,
+                        idx,
+                    });
+                }
+                leaf => {
+                    let writes = writes.filter(|&writes| self.unchanged_since(writes));
+                    return Ok((leaf, Trail { levels, writes }));
+                }
+            }
+        }
+    }
+
+    /// Reads the leaf next to the one `path` leads to, through the nearest node above
+    /// with a child on that side. Without latches this only holds while no writer
+    /// changed the tree since `path` was read, `None` tells to descend from the root
+    /// instead.
+    fn walk(self, path: Option<Trail>, step: Step) -> Result<Option<Descent>> {
+        let Some(mut path) = path else {
+            return Ok(None);
+        };
+        let Some(writes) = path.writes.filter(|&writes| self.unchanged_since(writes)) else {
+            return Ok(None);
+        };
+        loop {
+            let Some(level) = path.levels.last_mut() else {
+                return Ok(None);
+            };
+            match step {
+                Step::Next if level.idx + 1 < level.children.len() => level.idx += 1,
+                Step::Prev if level.idx > 0 => level.idx -= 1,
+                _ => {
+                    path.levels.pop();
+                    continue;
+                }
+            }
+            break;
+        }
+        let level = path.levels.last().unwrap();
+        let mut page = level.children[level.idx];
+        let node = loop {
+            let node = self.read_node(page);
+            match node {
+                Ok(Node::Internal { keys, children }) => {
+                    let idx = match step {
+                        Step::Next => 0,
+                        Step::Prev => keys.len(),
+                    };
+                    page = children[idx];
+                    path.levels.push(Level {
+                        keys,
+                        children,
+                        idx,
+                    });
+                }
+                _ => break node,
+            }
+        };
+        // Whatever was read, a writer got in between and a descent has to read it again.
+        if !self.unchanged_since(writes) {
+            return Ok(None);
+        }
+        Ok(Some((node?, path)))
+    }
+
+    /// The count of writes the tree stands at while no writer is busy. A snapshot never
+    /// changes.
+    fn quiet(self) -> Option<u64> {
+        match self.tree {
+            Some(tree) => tree.writes.quiet(),
+            None => Some(0),
+        }
+    }
+
+    fn unchanged_since(self, writes: u64) -> bool {
+        self.tree
+            .is_none_or(|tree| tree.writes.unchanged_since(writes))
+    }
+
+    fn open_value(self, value: LeafValue) -> ValueReader<'a> {
+        match value {
+            LeafValue::Inline(chunk) => ValueReader {
+                tree: self,
+                chunk,
+                pos: 0,
+                next: 0,
+                remaining: 0,
+            },
+            LeafValue::Overflow { len, first } => ValueReader {
+                tree: self,
+                chunk: vec![],
+                pos: 0,
+                next: first,
+                remaining: len as usize,
+            },
+        }
+    }
+
+    fn read_value(self, value: LeafValue) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.open_value(value).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_node(self, page: u32) -> Result<Node> {
+        let page = self.pager.get_page(self.file, page)?;
+        Ok(Node::from_page(&page, self.kind)?)
+    }
+}
+
+/// A committed version of a tree, readable while the tree goes on changing. The pages
+/// of that version stay allocated until the snapshot is dropped.
+pub struct Snapshot {
+    pager: Arc<Pager>,
+    file: FileId,
+    kind: TreeKind,
+    root: u32,
+    epoch: u64,
+    versions: Arc<Mutex<Versions>>,
+}
+
+impl Snapshot {
+    fn view(&self) -> View<'_> {
+        View {
+            pager: &self.pager,
+            file: self.file,
+            kind: self.kind,
+            root: self.root,
+            tree: None,
+        }
+    }
+
+    pub fn search(&self, key: &[u8]) -> Result<Option<KeyValuePair>> {
+        self.view().search(key)
+    }
+
+    pub fn value_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        self.view().value_reader(key)
+    }
+
+    pub fn range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Range<'_> {
+        self.view().range(range)
+    }
+
+    pub fn prefix(&self, prefix: &[u8]) -> Range<'_> {
+        self.view().prefix(prefix)
+    }
+
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.view().keys()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut versions = self.versions.lock().unwrap();
+        if let Some(count) = versions.readers.get_mut(&self.epoch) {
+            *count -= 1;
+            if *count == 0 {
+                versions.readers.remove(&self.epoch);
+            }
+        }
+        versions.reclaim(&self.pager, self.file);
+    }
+}
+
+/// The working version of a tree, changed by any number of threads at once.
+struct Working {
+    /// Root of the working version, the committed one until something changes.
+    root: u32,
+    /// Pages allocated since the last commit, changed in place.
+    fresh: HashSet<u32>,
+    /// Pages the working version no longer uses. They are freed with the next commit,
+    /// so a reader never follows a page number that was reused under it.
+    retiring: Vec<u32>,
+}
+
+/// A copy-on-write B+tree stored in a file of pages, read and written through a shared
+/// `Pager`. Changes never touch a page of the committed version: the first change to a
+/// page since the last commit copies it, and so its parent, up to a new root. `commit`
+/// publishes that root, to snapshots and to the meta page on the next flush.
+///
+/// Inserts, removals and reads of the working version can run on many threads at once.
+/// They latch their way down from the root, and a writer lets go of the nodes above one
+/// that was already copied and has room for the change.
+pub struct Btree {
+    pager: Arc<Pager>,
+    file: FileId,
+    kind: TreeKind,
+    working: Mutex<Working>,
+    latches: Latches,
+    /// Lets range scans walk on from the nodes they read while no writer got in between.
+    writes: Writes,
+    versions: Arc<Mutex<Versions>>,
+}
+
+impl Btree {
+    /// Opens the table tree stored at `path`, creating the file with an empty root leaf.
+    pub fn open<P: AsRef<Path>>(pager: &Arc<Pager>, path: P) -> Result<Btree> {
+        Self::open_kind(pager, path, TreeKind::Table)
+    }
+
+    /// Opens the index tree stored at `path`, creating the file with an empty root leaf.
+    pub fn open_index<P: AsRef<Path>>(pager: &Arc<Pager>, path: P) -> Result<Btree> {
+        Self::open_kind(pager, path, TreeKind::Index)
+    }
+
+    fn open_kind<P: AsRef<Path>>(pager: &Arc<Pager>, path: P, kind: TreeKind) -> Result<Btree> {
+        let file = pager.open_file(path)?;
+        let mut root = pager.root(file);
+        let created = root == 0;
+        if created {
+            root = pager.allocate_page(file);
+            pager.set_root(file, root);
+        }
+        let tree = Btree {
+            pager: pager.clone(),
+            file,
+            kind,
+            working: Mutex::new(Working {
+                root,
+                fresh: HashSet::new(),
+                retiring: vec![],
+            }),
+            latches: Latches::default(),
+            writes: Writes::default(),
+            versions: Arc::new(Mutex::new(Versions {
+                epoch: 0,
+                root,
+                readers: BTreeMap::new(),
+                retired: VecDeque::new(),
+            })),
+        };
+        if created {
+            tree.write_node(root, &Node::new_leaf())?;
+        } else {
+            // A file holding the other kind of tree fails here rather than on first use.
+            tree.read_node(root)?;
+        }
+        Ok(tree)
+    }
+
+    fn view(&self) -> View<'_> {
+        View {
+            pager: &self.pager,
+            file: self.file,
+            kind: self.kind,
+            root: 0,
+            tree: Some(self),
+        }
+    }
+
+    fn root(&self) -> u32 {
+        self.working.lock().unwrap().root
+    }
+
+    fn set_root(&self, root: u32) {
+        self.working.lock().unwrap().root = root;
+    }
+
+    /// Takes a snapshot of the committed version, changes since the last commit are not
+    /// part of it.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut versions = self.versions.lock().unwrap();
+        let epoch = versions.epoch;
+        *versions.readers.entry(epoch).or_default() += 1;
+        Snapshot {
+            pager: self.pager.clone(),
+            file: self.file,
+            kind: self.kind,
+            root: versions.root,
+            epoch,
+            versions: self.versions.clone(),
+        }
+    }
+
+    /// Publishes the working version. Pages only older versions use are freed once no
+    /// snapshot reads them.
+    pub fn commit(&mut self) {
+        let working = self.working.get_mut().unwrap();
+        let mut versions = self.versions.lock().unwrap();
+        if working.root == versions.root && working.fresh.is_empty() {
+            return;
+        }
+        let mut retired = vec![];
+        for page in working.retiring.drain(..) {
+            // Pages that never were part of a committed version are free right away.
+            if working.fresh.remove(&page) {
+                self.pager.free_page(self.file, page);
+            } else {
+                self.pager.retire_page(self.file, page);
+                retired.push(page);
+            }
+        }
+        let epoch = versions.epoch;
+        versions.retired.push_back((epoch, retired));
+        versions.epoch += 1;
+        versions.root = working.root;
+        self.pager.set_root(self.file, working.root);
+        working.fresh.clear();
+        versions.reclaim(&self.pager, self.file);
+    }
+
+    /// Drops the changes since the last commit, going back to the committed version.
+    pub fn rollback(&mut self) {
+        let working = self.working.get_mut().unwrap();
+        for page in working.fresh.drain() {
+            self.pager.free_page(self.file, page);
+        }
+        working.retiring.clear();
+        working.root = self.versions.lock().unwrap().root;
+    }
+
+    /// Index trees store keys alone, there is no room for a value.
+    fn check_value(&self, value: &[u8]) -> Result<()> {
+        if self.kind == TreeKind::Index && !value.is_empty() {
+            return Err(Error::InvalidInput(
+                "index trees store keys without values".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reserves a page for the working version.
+    fn allocate(&self) -> u32 {
+        let page = self.pager.allocate_page(self.file);
+        self.working.lock().unwrap().fresh.insert(page);
+        page
+    }
+
+    fn is_fresh(&self, page: u32) -> bool {
+        self.working.lock().unwrap().fresh.contains(&page)
+    }
+
+    /// Page to write a changed node read from `page` to: `page` itself if it was
+    /// allocated since the last commit, otherwise a copy that replaces it.
+    fn writable(&self, page: u32) -> u32 {
+        if self.is_fresh(page) {
+            return page;
+        }
+        self.release(page);
+        self.allocate()
+    }
+
+    /// Gives back a page the working version no longer uses.
+    fn release(&self, page: u32) {
+        self.working.lock().unwrap().retiring.push(page);
+    }
+
+    pub fn search(&self, key: &[u8]) -> Result<Option<KeyValuePair>> {
+        self.view().search(key)
+    }
+
+    /// Opens the value of `key` for reading, a page at a time if it spilled into
+    /// overflow pages.
+    pub fn value_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        self.view().value_reader(key)
+    }
+
+    /// Inserts a pair, replacing the value of an existing key. Returns the previous pair.
+    pub fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<Option<KeyValuePair>> {
+        self.upsert(key, value, ConflictPolicy::Overwrite)
+    }
+
+    /// Inserts a pair, resolving an existing key with `policy`. Returns the previous pair.
+    pub fn upsert(
+        &self,
+        key: &[u8],
+        value: Vec<u8>,
+        policy: ConflictPolicy,
+    ) -> Result<Option<KeyValuePair>> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(Error::KeyTooLarge(key.len()));
+        }
+        self.check_value(&value)?;
+        let _writing = self.writes.begin();
+        let mut latches = vec![self.latches.exclusive(ROOT_LATCH)];
+        let mut root = self.root();
+        let (previous, split) = self.insert_into(&mut latches, &mut root, key, value, policy)?;
+        // A fresh node below absorbed the insert and let the root latch go, `root` is
+        // unchanged then and another writer may have published a newer one since.
+        if latches
+            .first()
+            .is_some_and(|latch| latch.page() == ROOT_LATCH)
+        {
+            if let Some((separator, right)) = split {
+                let node = Node::Internal {
+                    keys: vec![separator],
+                    children: vec![root, right],
+                };
+                root = self.write_new(&node)?;
+            }
+            self.set_root(root);
+        }
+        Ok(previous)
+    }
+
+    /// Inserts into the sub tree rooted at `page`, returning the previous pair. `page` is
+    /// updated when the node there is copied. When the node overflows it is split, and
+    /// the separator key and page of the new right half are returned too. `latches`
+    /// holds the latches of the nodes above that this insert may still change.
+    fn insert_into<'a>(
+        &'a self,
+        latches: &mut Vec<Latch<'a>>,
+        page: &mut u32,
+        key: &[u8],
+        value: Vec<u8>,
+        policy: ConflictPolicy,
+    ) -> Result<(Option<KeyValuePair>, Option<Split>)> {
+        latches.push(self.latches.exclusive(*page));
+        let mut node = self.read_node(*page)?;
+        if self.is_fresh(*page) && node.absorbs(Change::Insert) {
+            latches.drain(..latches.len() - 1);
+        }
+        let previous = match &mut node {
+            Node::Leaf { keys, values } => {
+                let pos = keys.binary_search_by(|probe| probe.as_slice().cmp(key));
+                let previous = match pos {
+                    Ok(pos) => Some(self.read_value(values[pos].clone())?),
+                    Err(_) => None,
+                };
+                let value = match (&previous, policy) {
+                    (Some(_), ConflictPolicy::Keep) => {
+                        let previous = previous.map(|value| KeyValuePair::new(key.to_vec(), value));
+                        return Ok((previous, None));
+                    }
+                    (Some(previous), ConflictPolicy::Merge(merge)) => merge(previous, &value),
+                    _ => value,
+                };
+                let value = self.write_value(key, value)?;
+                match pos {
+                    Ok(pos) => {
+                        let replaced = std::mem::replace(&mut values[pos], value);
+                        self.free_value(&replaced)?;
+                    }
+                    Err(pos) => {
+                        keys.insert(pos, key.to_vec());
+                        values.insert(pos, value);
+                    }
+                }
+                previous.map(|value| KeyValuePair::new(key.to_vec(), value))
+            }
+            Node::Internal { keys, children } => {
+                let idx = route(keys, key);
+                let child = children[idx];
+                match self.insert_into(latches, &mut children[idx], key, value, policy)? {
+                    (previous, Some((separator, right))) => {
+                        keys.insert(idx, separator);
+                        children.insert(idx + 1, right);
+                        previous
+                    }
+                    // The child was copied, this node has to point at the copy.
+                    (previous, None) if children[idx] != child => previous,
+                    unsplit => return Ok(unsplit),
+                }
+            }
+        };
+
+        *page = self.writable(*page);
+        if !node.is_full() {
+            self.write_node(*page, &node)?;
+            return Ok((previous, None));
+        }
+        let (left, separator, right) = node.split();
+        let right_page = self.write_new(&right)?;
+        self.write_node(*page, &left)?;
+        Ok((previous, Some((separator, right_page))))
+    }
+
+    /// Pairs with keys in `range` in ascending order, or descending with `.rev()`. Each
+    /// leaf is read as it is when the range gets to it, a consistent view of the tree
+    /// takes a `snapshot`.
+    pub fn range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Range<'_> {
+        self.view().range(range)
+    }
+
+    /// Pairs whose keys start with `prefix`, such as every point of one series.
+    pub fn prefix(&self, prefix: &[u8]) -> Range<'_> {
+        self.view().prefix(prefix)
+    }
+
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = Result<Vec<u8>>> + '_ {
+        self.view().keys()
+    }
+
+    fn read_value(&self, value: LeafValue) -> Result<Vec<u8>> {
+        self.view().read_value(value)
+    }
+
+    /// Prepares a value for its leaf cell, writing it to a chain of overflow pages if it
+    /// does not fit next to `key`.
+    fn write_value(&self, key: &[u8], value: Vec<u8>) -> Result<LeafValue> {
+        if key.len() + value.len() <= MAX_PAIR_SIZE {
+            return Ok(LeafValue::Inline(value));
+        }
+        let len = u32::try_from(value.len()).map_err(|_| Error::ValueTooLarge(value.len()))?;
+        let chunks: Vec<&[u8]> = value.chunks(OVERFLOW_CAPACITY).collect();
+        let pages: Vec<u32> = chunks.iter().map(|_| self.allocate()).collect();
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let next = pages.get(idx + 1).copied().unwrap_or(0);
+            let page = Page::overflow(pages[idx], next, chunk)?;
+            self.pager.write_page(self.file, page)?;
+        }
+        Ok(LeafValue::Overflow {
+            len,
+            first: pages[0],
+        })
+    }
+
+    /// Releases the overflow pages of a value that is no longer stored.
+    fn free_value(&self, value: &LeafValue) -> Result<()> {
+        let LeafValue::Overflow { mut len, first } = *value else {
+            return Ok(());
+        };
+        let mut page = first;
+        while len > 0 {
+            let next = overflow_header(&*self.pager.get_page(self.file, page)?)?.right_pointer;
+            self.release(page);
+            len = len.saturating_sub(OVERFLOW_CAPACITY as u32);
+            page = next;
+        }
+        Ok(())
+    }
+
+    fn read_node(&self, page: u32) -> Result<Node> {
+        self.view().read_node(page)
+    }
+
+    fn write_node(&self, page: u32, node: &Node) -> Result<()> {
+        Ok(self
+            .pager
+            .write_page(self.file, node.to_page(page, self.kind)?)?)
+    }
+
+    /// Writes a node to a newly allocated page and returns the page.
+    fn write_new(&self, node: &Node) -> Result<u32> {
+        let page = self.allocate();
+        self.write_node(page, node)?;
+        Ok(page)
+    }
+
+    /// Removes a key and returns its pair. Pages emptied by merges are released, and the
+    /// tree loses a level when the root is left with a single child.
+    pub fn remove(&self, key: &[u8]) -> Result<Option<KeyValuePair>> {
+        let _writing = self.writes.begin();
+        let mut latches = vec![self.latches.exclusive(ROOT_LATCH)];
+        let mut root = self.root();
+        let (removed, _) = self.remove_from(&mut latches, &mut root, key)?;
+        if latches
+            .first()
+            .is_some_and(|latch| latch.page() == ROOT_LATCH)
+        {
+            self.set_root(root);
+            if let Node::Internal { keys, children } = self.read_node(root)? {
+                if keys.is_empty() {
+                    self.set_root(children[0]);
+                    self.release(root);
+                }
             }
+        }
+        Ok(removed)
+    }
+
+    /// Removes from the sub tree rooted at `page`, returning the pair and whether the
+    /// node there is left underfull for its parent to fix. `page` is updated when the
+    /// node there is copied, `latches` holds the nodes above it may still change.
+    fn remove_from<'a>(
+        &'a self,
+        latches: &mut Vec<Latch<'a>>,
+        page: &mut u32,
+        key: &[u8],
+    ) -> Result<(Option<KeyValuePair>, bool)> {
+        latches.push(self.latches.exclusive(*page));
+        let mut node = self.read_node(*page)?;
+        if self.is_fresh(*page) && node.absorbs(Change::Remove) {
+            latches.drain(..latches.len() - 1);
+        }
+        let mut resized = true;
+        let removed = match &mut node {
+            Node::Leaf { .. } => match node.remove(key) {
+                Some(value) => {
+                    let pair = KeyValuePair::new(key.to_vec(), self.read_value(value.clone())?);
+                    self.free_value(&value)?;
+                    Some(pair)
+                }
+                None => None,
+            },
             Node::Internal { keys, children } => {
-                let (keys, children) = (keys.clone(), children.clone());
-                let left = Node::Internal {
-                    keys: keys[..mid].to_vec(),
-                    children: children[..mid + 1].to_vec(),
-                };
-                let right = Node::Internal {
-                    keys: keys[mid + 1..].to_vec(),
-                    children: children[mid + 1..].to_vec(),
-                };
-                (left, keys[mid], right)
+                let idx = route(keys, key);
+                let child = children[idx];
+                let (removed, underflow) = self.remove_from(latches, &mut children[idx], key)?;
+                if underflow && children.len() >= 2 {
+                    self.rebalance(keys, children, idx)?;
+                } else if children[idx] != child {
+                    // Only the child was copied, this node keeps its size.
+                    resized = false;
+                } else {
+                    return Ok((removed, false));
+                }
+                removed
             }
+        };
+        if removed.is_none() {
+            return Ok((None, false));
         }
+        *page = self.writable(*page);
+        self.write_node(*page, &node)?;
+        Ok((removed, resized && node.is_underflow()))
     }
 
-    // Insert a key-value pair into the B+ tree
-    fn insert(&mut self, key: u64, value: Vec<u8>) {
-        match self {
-            Node::Leaf { keys, values } => {
-                match keys.binary_search(&key) {
-                    Ok(pos) => panic!("key '{pos}' already exists"),
-                    Err(pos) => {
-                        keys.insert(pos, key);
-                        values.insert(pos, KeyValuePair { key, value });
-                        pos
-                    }
-                };
+    /// Fixes the underfull child at `idx` together with a neighbour. If both fit in one
+    /// page they are merged and the right page released, otherwise their entries are
+    /// split evenly between them again. The child is latched already, the neighbour is
+    /// latched here.
+    fn rebalance(
+        &self,
+        keys: &mut Vec<Vec<u8>>,
+        children: &mut Vec<u32>,
+        idx: usize,
+    ) -> Result<()> {
+        let (left_idx, neighbour) = if idx + 1 < children.len() {
+            (idx, idx + 1)
+        } else {
+            (idx - 1, idx - 1)
+        };
+        let _latch = self.latches.exclusive(children[neighbour]);
+        let (left_page, right_page) = (children[left_idx], children[left_idx + 1]);
+        let left = self.read_node(left_page)?;
+        let right = self.read_node(right_page)?;
+        let mut merged = left.merge(keys[left_idx].clone(), right);
+        if !merged.is_full() {
+            children[left_idx] = self.writable(left_page);
+            self.write_node(children[left_idx], &merged)?;
+            keys.remove(left_idx);
+            children.remove(left_idx + 1);
+            self.release(right_page);
+        } else {
+            let (left, separator, right) = merged.split();
+            children[left_idx] = self.writable(left_page);
+            children[left_idx + 1] = self.writable(right_page);
+            self.write_node(children[left_idx], &left)?;
+            self.write_node(children[left_idx + 1], &right)?;
+            keys[left_idx] = separator;
+        }
+        Ok(())
+    }
 
-                // split leaf node if it has become too large
-                if self.is_full() {
-                    let (left, new_key, right) = self.split();
+    /// Builds the tree bottom-up from `pairs`, which must come in strictly ascending key
+    /// order, while it is still empty. Leaves are written as they fill up to
+    /// `fill_factor` of a page and interior levels are built over them, leaving room for
+    /// later inserts without the splits and half-empty pages of inserting one by one.
+    /// Like any other change the loaded pairs are published by `commit`.
+    pub fn bulk_load<I>(&mut self, pairs: I, fill_factor: f64) -> Result<()>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+            return Err(Error::InvalidInput(format!(
+                "fill factor {fill_factor} is outside (0, 1]"
+            )));
+        }
+        let empty = self.root();
+        if self.read_node(empty)? != Node::new_leaf() {
+            return Err(Error::InvalidInput(
+                "bulk loading needs an empty tree".to_owned(),
+            ));
+        }
+        let target = (USABLE_SIZE as f64 * fill_factor) as usize;
 
-                    *self = Node::Internal {
-                        keys: vec![new_key],
-                        children: vec![left, right],
-                    };
-                }
+        // Page and largest key of every node on the level being built.
+        let mut level: Vec<(u32, Vec<u8>)> = vec![];
+        let mut keys: Vec<Vec<u8>> = vec![];
+        let mut values = vec![];
+        let mut size = PAGE_HEADER_SIZE;
+        for (key, value) in pairs {
+            if key.len() > MAX_KEY_SIZE {
+                return Err(Error::KeyTooLarge(key.len()));
             }
-            Node::Internal { keys, children } => {
-                let pos = match keys.binary_search(&key) {
-                    Ok(_) => panic!("Duplicate key"),
-                    Err(pos) => pos,
+            let last = keys.last().or(level.last().map(|(_, last)| last));
+            if last.is_some_and(|last| *last >= key) {
+                return Err(Error::InvalidInput(
+                    "bulk load keys are not in ascending order".to_owned(),
+                ));
+            }
+            self.check_value(&value)?;
+            let value = self.write_value(&key, value)?;
+            let cell = LEAF_CELL_OVERHEAD + key.len() + value.cell_len();
+            if !keys.is_empty() && size + cell > target {
+                let max = keys.last().unwrap().clone();
+                let leaf = Node::Leaf {
+                    keys: std::mem::take(&mut keys),
+                    values: std::mem::take(&mut values),
                 };
-
-                // call child to insert data
-                let child = &mut children[pos];
-                child.insert(key, value);
-                if child.is_full() {
-                    let (left, new_key, right) = child.split();
-                    *child = left;
-                    keys.insert(pos, new_key);
-                    children.insert(pos + 1, right);
-                    // return left;
-                }
-                // return self;
+                level.push((self.write_new(&leaf)?, max));
+                size = PAGE_HEADER_SIZE;
             }
+            size += cell;
+            keys.push(key);
+            values.push(value);
         }
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let max = keys.last().unwrap().clone();
+        let leaf = Node::Leaf { keys, values };
+        level.push((self.write_new(&leaf)?, max));
+
+        while level.len() > 1 {
+            level = self.build_level(level, target)?;
+        }
+        self.set_root(level[0].0);
+        self.release(empty);
+        Ok(())
     }
 
-    fn search_leaf(&self, key: u64) -> &Self {
-        match &self {
-            Node::Internal { keys, children } => match keys.binary_search(&key) {
-                Ok(index) => children[index].search_leaf(key),
-                Err(_) => panic!(),
-            },
-            Node::Leaf { .. } => self,
-        }
-    }
-
-    // fn remove(&mut self, key: u64) -> Option<KeyValuePair> {
-    //     let index = match self.keys.binary_search(&key) {
-    //         Ok(i) => i,
-    //         Err(i) => i,
-    //     };
-
-    //     // If the key is in the current node, remove it
-    //     if index < self.keys.len() && self.keys[index] == key {
-    //         // If this is a leaf node, remove the key-value pair directly
-    //         if self.is_leaf() {
-    //             let value = self.values.remove(index);
-    //             self.keys.remove(index);
-    //             return Some(value);
-    //         }
-
-    //         // If this is an internal node, replace the key with the maximum key in the left child
-    //         let mut left_child = &mut self.children.as_mut().unwrap()[index];
-    //         while let Some(ref mut child) = left_child.children {
-    //             let len = child.len();
-    //             left_child = &mut child[len - 1];
-    //         }
-    //         let replacement_key = left_child.keys.pop().unwrap();
-    //         let replacement_value = left_child.values.pop().unwrap();
-    //         self.keys[index] = replacement_key.clone();
-    //         self.values[index] = replacement_value;
-    //         return left_child.remove(replacement_key);
-    //     }
-
-    //     // If the key is not in this node, continue down the children
-    //     if let Some(ref mut children) = self.children {
-    //         let child = &mut children[index];
-    //         return child.remove(key);
-    //     }
-
-    //     // If we reach here, the key does not exist in the btree
-    //     None
-    // }
-}
-
-#[derive(Debug)]
-pub struct Btree {
-    root: Node,
-}
+    /// Writes the interior nodes over `children`, the page and largest key of each node
+    /// on the level below, and returns the same for the new nodes.
+    fn build_level(
+        &mut self,
+        children: Vec<(u32, Vec<u8>)>,
+        target: usize,
+    ) -> Result<Vec<(u32, Vec<u8>)>> {
+        // The largest key of a child becomes a separator cell once another child follows.
+        let mut groups: Vec<Vec<(u32, Vec<u8>)>> = vec![];
+        let mut size = PAGE_HEADER_SIZE;
+        for child in children {
+            let Some(group) = groups.last_mut() else {
+                groups.push(vec![child]);
+                continue;
+            };
+            let cell = INTERIOR_CELL_OVERHEAD + group.last().unwrap().1.len();
+            if group.len() >= 2 && size + cell > target {
+                groups.push(vec![child]);
+                size = PAGE_HEADER_SIZE;
+            } else {
+                group.push(child);
+                size += cell;
+            }
+        }
+        // A lone last child joins the node before it, which is split if that overfills it.
+        if groups.len() > 1 && groups.last().unwrap().len() == 1 {
+            let lone = groups.pop().unwrap();
+            groups.last_mut().unwrap().extend(lone);
+        }
 
-impl Btree {
-    pub fn new() -> Self {
-        Btree {
-            root: Node::new_leaf(),
+        let mut parents = Vec::with_capacity(groups.len());
+        for group in groups {
+            let children = group.iter().map(|(page, _)| *page).collect();
+            let mut keys: Vec<Vec<u8>> = group.into_iter().map(|(_, key)| key).collect();
+            let max = keys.pop().unwrap();
+            let mut node = Node::Internal { keys, children };
+            if node.is_full() {
+                let (left, separator, right) = node.split();
+                parents.push((self.write_new(&left)?, separator));
+                node = right;
+            }
+            parents.push((self.write_new(&node)?, max));
         }
+        Ok(parents)
     }
 
-    pub fn search(&self, key: u64) -> Option<KeyValuePair> {
-        self.search_node(&self.root, key)
+    /// Commits, then moves the pages in use at the end of the file into the free pages
+    /// before them and drops the free list, so the file shrinks to the pages in use on
+    /// the next flush. Pages are moved in place, which no snapshot may be reading.
+    pub fn vacuum(&mut self) -> Result<()> {
+        self.commit();
+        let readers = self.versions.lock().unwrap().readers.values().sum();
+        if readers > 0 {
+            return Err(Error::SnapshotsOpen(readers));
+        }
+        let free: BTreeSet<u32> = self.pager.free_pages(self.file).into_iter().collect();
+        let page_count = self.pager.page_count(self.file);
+        let target = page_count - free.len() as u32;
+        // Exactly as many pages are in use past `target` as are free before it.
+        let moved: HashMap<u32, u32> = (target..page_count)
+            .filter(|page| !free.contains(page))
+            .zip(free.range(..target).copied())
+            .collect();
+        if !moved.is_empty() {
+            let root = self.root();
+            self.relocate(root, &moved)?;
+            let root = moved.get(&root).copied().unwrap_or(root);
+            self.set_root(root);
+            self.versions.lock().unwrap().root = root;
+            self.pager.set_root(self.file, root);
+        }
+        self.pager.truncate(self.file, target);
+        Ok(())
     }
 
-    /// search_node recursively searches a sub tree rooted at node for a key.
-    fn search_node(&self, node: &Node, search: u64) -> Option<KeyValuePair> {
-        match &node {
-            Node::Internal { children, keys } => {
-                let idx = keys.binary_search(&search).unwrap_or_else(|x| x);
-                // Retrieve child page from disk and deserialize.
-                let child_node = children.get(idx).unwrap();
-                // let page = self.pager.get_page(child_offset)?;
-                // let child_node = Node::try_from(page)?;
-                self.search_node(child_node, search)
+    /// Rewrites the sub tree rooted at `page` with the page numbers in `moved` replaced,
+    /// writing moved pages to their new place.
+    fn relocate(&self, page: u32, moved: &HashMap<u32, u32>) -> Result<()> {
+        let to = |page: u32| moved.get(&page).copied().unwrap_or(page);
+        let mut node = self.read_node(page)?;
+        let mut changed = moved.contains_key(&page);
+        match &mut node {
+            Node::Internal { children, .. } => {
+                for child in children.iter_mut() {
+                    self.relocate(*child, moved)?;
+                    changed |= moved.contains_key(child);
+                    *child = to(*child);
+                }
             }
             Node::Leaf { values, .. } => {
-                if let Ok(idx) = values.binary_search_by_key(&search, |pair| pair.key) {
-                    return Some(values[idx].clone());
+                for value in values.iter_mut() {
+                    if let LeafValue::Overflow { first, .. } = value {
+                        self.relocate_chain(*first, moved)?;
+                        changed |= moved.contains_key(first);
+                        *first = to(*first);
+                    }
                 }
-                None
-                // Err(Error::KeyNotFound)
-            } // Node::Unexpected => Err(Error::UnexpectedError),
+            }
         }
+        if changed {
+            self.write_node(to(page), &node)?;
+        }
+        Ok(())
     }
 
-    pub fn insert(&mut self, key: u64, value: Vec<u8>) {
-        self.root.insert(key, value);
-    }
-
-    pub fn keys(&self) -> KeysIterator {
-        let mut iterator = KeysIterator::default();
-        iterator.queue.push_back(&self.root);
-        iterator
+    fn relocate_chain(&self, first: u32, moved: &HashMap<u32, u32>) -> Result<()> {
+        let to = |page: u32| moved.get(&page).copied().unwrap_or(page);
+        let mut page = first;
+        while page != 0 {
+            let overflow = self.pager.get_page(self.file, page)?;
+            let next = overflow_header(&overflow)?.right_pointer;
+            if moved.contains_key(&page) || moved.contains_key(&next) {
+                let relocated = Page::overflow(to(page), to(next), overflow.payload())?;
+                self.pager.write_page(self.file, relocated)?;
+            }
+            page = next;
+        }
+        Ok(())
     }
-
-    // pub fn remove(&mut self, key: u64) -> Option<KeyValuePair> {
-    //     self.root.remove(key)
-    // }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::check::check_file;
+    use crate::storage::paging::PAGE_SIZE;
+
+    fn int_key(n: u64) -> Vec<u8> {
+        n.to_be_bytes().to_vec()
+    }
+
+    fn from_int_key(key: &[u8]) -> u64 {
+        u64::from_be_bytes(key.try_into().unwrap())
+    }
+
+    fn stored_keys(btree: &Btree) -> Vec<u64> {
+        btree
+            .keys()
+            .map(|key| from_int_key(&key.unwrap()))
+            .collect()
+    }
+
+    fn stored_keys_of(snapshot: &Snapshot) -> Vec<u64> {
+        snapshot
+            .keys()
+            .map(|key| from_int_key(&key.unwrap()))
+            .collect()
+    }
 
     #[test]
     fn split_leaf_works() {
         let mut node = Node::Leaf {
             values: vec![
-                KeyValuePair::new(1, "bar".as_bytes().to_vec()),
-                KeyValuePair::new(2, "james".as_bytes().to_vec()),
-                KeyValuePair::new(3, "grande".as_bytes().to_vec()),
+                LeafValue::Inline("bar".as_bytes().to_vec()),
+                LeafValue::Inline("james".as_bytes().to_vec()),
+                LeafValue::Inline("grande".as_bytes().to_vec()),
             ],
-            keys: vec![1, 2, 3],
+            keys: vec![int_key(1), int_key(2), int_key(3)],
         };
 
         let (left, mid, sibling) = node.split();
         println!("SPLIT LEAF {left:?} | {sibling:?}");
-        assert_eq!(mid, 2);
+        assert_eq!(mid, int_key(2));
         assert_eq!(
             left,
             Node::Leaf {
                 values: vec![
-                    KeyValuePair {
-                        key: 1,
-                        value: "bar".as_bytes().to_vec(),
-                    },
-                    KeyValuePair {
-                        key: 2,
-                        value: "james".as_bytes().to_vec()
-                    }
+                    LeafValue::Inline("bar".as_bytes().to_vec()),
+                    LeafValue::Inline("james".as_bytes().to_vec()),
                 ],
-                keys: vec![1, 2],
+                keys: vec![int_key(1), int_key(2)],
             }
         );
         assert_eq!(
             sibling,
             Node::Leaf {
-                keys: vec![3],
-                values: vec![KeyValuePair::new(3, "grande".as_bytes().to_vec())]
+                keys: vec![int_key(3)],
+                values: vec![LeafValue::Inline("grande".as_bytes().to_vec())],
             }
         );
     }
@@ -350,224 +1738,952 @@ mod tests {
     #[test]
     fn split_internal_works() {
         let mut node = Node::Internal {
-            // vec![
-            //     Offset(PAGE_SIZE),
-            //     Offset(PAGE_SIZE * 2),
-            //     Offset(PAGE_SIZE * 3),
-            //     Offset(PAGE_SIZE * 4),
-            // ],
-            children: vec![
-                Node::Leaf {
-                    keys: vec![],
-                    values: vec![KeyValuePair::new(1, "bar".as_bytes().to_vec())],
-                },
-                Node::Leaf {
-                    keys: vec![],
-                    values: vec![KeyValuePair::new(2, "james".as_bytes().to_vec())],
-                },
-                Node::Leaf {
-                    keys: vec![],
-                    values: vec![KeyValuePair::new(3, "grande".as_bytes().to_vec())],
-                },
-            ],
-            keys: vec![
-                1, // Key("foo bar".to_string()),
-                2, // Key("lebron".to_string()),
-                3, // Key("ariana".to_string()),
-            ],
+            children: vec![1, 2, 3, 4],
+            keys: vec![b"ariana".to_vec(), b"foo bar".to_vec(), b"lebron".to_vec()],
         };
 
         let (left, median, sibling) = node.split();
-        println!("AFTER SPLIT {} -> {:?}", median, sibling);
-        assert_eq!(median, 2);
+        println!("AFTER SPLIT {median:?} -> {sibling:?}");
+        assert_eq!(median, b"foo bar");
         assert_eq!(
             left,
             Node::Internal {
-                // vec![Offset(PAGE_SIZE), Offset(PAGE_SIZE * 2)],
-                keys: vec![1],
-                children: vec![
-                    Node::Leaf {
-                        keys: vec![],
-                        values: vec![KeyValuePair::new(1, "bar".as_bytes().to_vec(),)],
-                    },
-                    Node::Leaf {
-                        keys: vec![],
-                        values: vec![KeyValuePair::new(2, "james".as_bytes().to_vec(),)],
-                    }
-                ]
+                keys: vec![b"ariana".to_vec()],
+                children: vec![1, 2]
             }
         );
         assert_eq!(
             sibling,
             Node::Internal {
-                // vec![Offset(PAGE_SIZE * 3), Offset(PAGE_SIZE * 4)],
-                keys: vec![3],
-                children: vec![Node::Leaf {
-                    keys: vec![],
-                    values: vec![KeyValuePair::new(3, "grande".as_bytes().to_vec(),)],
-                },],
+                keys: vec![b"lebron".to_vec()],
+                children: vec![3, 4],
             }
         );
     }
 
     #[test]
     fn test_insert() {
-        let mut btree = Btree::new();
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(1024 * 1024));
+        let btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
 
         // Insert key-value pairs
-        btree.insert(1, "value1".as_bytes().to_vec());
-        btree.insert(2, "value2".as_bytes().to_vec());
-        btree.insert(3, "value3".as_bytes().to_vec());
-        btree.insert(4, "value4".as_bytes().to_vec());
-        btree.insert(5, "value5".as_bytes().to_vec());
-        btree.insert(6, "value6".as_bytes().to_vec());
-        btree.insert(7, "value7".as_bytes().to_vec());
-        btree.insert(8, "value8".as_bytes().to_vec());
-        btree.insert(9, "value9".as_bytes().to_vec());
-        btree.insert(10, "value10".as_bytes().to_vec());
-        btree.insert(11, "value11".as_bytes().to_vec());
-        btree.insert(12, "value12".as_bytes().to_vec());
-
-        println!("BTREE AFTER ALL {btree:?}");
+        btree
+            .insert(&int_key(1), "value1".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(2), "value2".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(3), "value3".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(4), "value4".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(5), "value5".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(6), "value6".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(7), "value7".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(8), "value8".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(9), "value9".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(10), "value10".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(11), "value11".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(12), "value12".as_bytes().to_vec())
+            .unwrap();
+
         // Check the values are stored correctly
         assert_eq!(
-            btree.search(1),
+            btree.search(&int_key(1)).unwrap(),
             Some(KeyValuePair {
-                key: 1,
+                key: int_key(1),
                 value: "value1".as_bytes().to_vec()
             })
         );
         assert_eq!(
-            btree.search(2),
+            btree.search(&int_key(2)).unwrap(),
             Some(KeyValuePair {
-                key: 2,
+                key: int_key(2),
                 value: "value2".as_bytes().to_vec()
             })
         );
         assert_eq!(
-            btree.search(3),
+            btree.search(&int_key(3)).unwrap(),
             Some(KeyValuePair {
-                key: 3,
+                key: int_key(3),
                 value: "value3".as_bytes().to_vec()
             })
         );
         assert_eq!(
-            btree.search(4),
+            btree.search(&int_key(4)).unwrap(),
             Some(KeyValuePair {
-                key: 4,
+                key: int_key(4),
                 value: "value4".as_bytes().to_vec()
             })
         );
         assert_eq!(
-            btree.search(5),
+            btree.search(&int_key(5)).unwrap(),
             Some(KeyValuePair {
-                key: 5,
+                key: int_key(5),
                 value: "value5".as_bytes().to_vec()
             })
         );
         assert_eq!(
-            btree.search(6),
+            btree.search(&int_key(6)).unwrap(),
             Some(KeyValuePair {
-                key: 6,
+                key: int_key(6),
                 value: "value6".as_bytes().to_vec()
             })
         );
         assert_eq!(
-            btree.search(7),
+            btree.search(&int_key(7)).unwrap(),
             Some(KeyValuePair {
-                key: 7,
+                key: int_key(7),
                 value: "value7".as_bytes().to_vec()
             })
         );
         assert_eq!(
-            btree.search(8),
+            btree.search(&int_key(8)).unwrap(),
             Some(KeyValuePair {
-                key: 8,
+                key: int_key(8),
                 value: "value8".as_bytes().to_vec()
             })
         );
         assert_eq!(
-            btree.search(9),
+            btree.search(&int_key(9)).unwrap(),
             Some(KeyValuePair {
-                key: 9,
+                key: int_key(9),
                 value: "value9".as_bytes().to_vec()
             })
         );
         assert_eq!(
-            btree.search(10),
+            btree.search(&int_key(10)).unwrap(),
             Some(KeyValuePair {
-                key: 10,
+                key: int_key(10),
                 value: "value10".as_bytes().to_vec()
             })
         );
         assert_eq!(
-            btree.search(11),
+            btree.search(&int_key(11)).unwrap(),
             Some(KeyValuePair {
-                key: 11,
+                key: int_key(11),
                 value: "value11".as_bytes().to_vec()
             })
         );
         assert_eq!(
-            btree.search(12),
+            btree.search(&int_key(12)).unwrap(),
             Some(KeyValuePair {
-                key: 12,
+                key: int_key(12),
                 value: "value12".as_bytes().to_vec()
             })
         );
 
         // Check the values are ordered correctly
-        let keys: Vec<u64> = btree.keys().collect();
+        let keys = stored_keys(&btree);
         assert_eq!(keys, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
     }
 
-    // #[test]
-    // fn test_remove() {
-    //     let mut btree = Btree::new();
-
-    //     // Insert key-value pairs
-    //     btree.insert(1, "value1");
-    //     btree.insert(2, "value2");
-    //     btree.insert(3, "value3");
-    //     btree.insert(4, "value4");
-    //     btree.insert(5, "value5");
-    //     btree.insert(6, "value6");
-    //     btree.insert(7, "value7");
-    //     btree.insert(8, "value8");
-
-    //     // Remove some values
-    //     btree.remove(3);
-    //     btree.remove(4);
-    //     btree.remove(8);
-
-    //     // Check the values were removed correctly
-    //     assert_eq!(btree.search(3), None);
-    //     assert_eq!(btree.search(4), None);
-    //     assert_eq!(btree.search(8), None);
-
-    //     // Check the values are still ordered correctly
-    //     let keys: Vec<&u32> = btree.keys().collect();
-    //     assert_eq!(keys, vec![&1, &2, &5, &6, &7]);
-    // }
-
-    // #[test]
-    // fn test_iteration() {
-    //     let mut btree = Btree::new();
-
-    //     // Insert key-value pairs
-    //     btree.insert(1, "value1");
-    //     btree.insert(2, "value2");
-    //     btree.insert(3, "value3");
-    //     btree.insert(4, "value4");
-    //     btree.insert(5, "value5");
-    //     btree.insert(6, "value6");
-    //     btree.insert(7, "value7");
-    //     btree.insert(8, "value8");
-
-    //     // Check the values are iterated correctly
-    //     let values: Vec<&str> = btree.values().map(|v| v.1).collect();
-    //     assert_eq!(
-    //         values,
-    //         vec!["value1", "value2", "value3", "value4", "value5"]
-    //     );
-    // }
+    #[test]
+    fn splits_across_pages_and_reopens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.db");
+        // A small cache makes the tree page in and out of its file.
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, &path).unwrap();
+        // Insert out of order so splits happen all over the tree.
+        let keys: Vec<u64> = (0..5000).map(|i| (i * 7919) % 5000).collect();
+        for key in &keys {
+            btree.insert(&int_key(*key), vec![*key as u8; 40]).unwrap();
+        }
+        btree.commit();
+        assert!(matches!(
+            btree.read_node(pager.root(btree.file)).unwrap(),
+            Node::Internal { .. }
+        ));
+        assert_eq!(pager.cached(), pager.capacity());
+        pager.flush().unwrap();
+        drop(btree);
+
+        let pager = Arc::new(Pager::new(0));
+        let btree = Btree::open(&pager, &path).unwrap();
+        assert_eq!(
+            btree.search(&int_key(4321)).unwrap(),
+            Some(KeyValuePair::new(int_key(4321), vec![4321u64 as u8; 40]))
+        );
+        assert_eq!(btree.search(&int_key(5000)).unwrap(), None);
+        assert_eq!(stored_keys(&btree), (0..5000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reports_corrupt_pages_by_number() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.db");
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, &path).unwrap();
+        for key in 0..1000u64 {
+            btree.insert(&int_key(key), vec![key as u8; 40]).unwrap();
+        }
+        btree.commit();
+        pager.flush().unwrap();
+        let Node::Internal { children, .. } = btree.read_node(btree.root()).unwrap() else {
+            unreachable!();
+        };
+        let next = children[1];
+        drop(btree);
+
+        // Flip one bit in the middle of the second leaf.
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[next as usize * PAGE_SIZE + PAGE_SIZE / 2] ^= 0x10;
+        std::fs::write(&path, bytes).unwrap();
+
+        let pager = Arc::new(Pager::new(0));
+        let btree = Btree::open(&pager, &path).unwrap();
+        assert!(btree.search(&int_key(1)).unwrap().is_some());
+        let err = btree.range(..).find_map(|pair| pair.err()).unwrap();
+        assert!(
+            matches!(err, Error::CorruptPage(page) if page == next),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn rejects_keys_larger_than_a_leaf_cell() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(1024 * 1024));
+        let btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
+        assert!(matches!(
+            btree.insert(&[7; MAX_KEY_SIZE + 1], vec![]),
+            Err(Error::KeyTooLarge(_))
+        ));
+        btree.insert(&[7; MAX_KEY_SIZE], vec![0; 100]).unwrap();
+        assert_eq!(
+            btree.search(&[7; MAX_KEY_SIZE]).unwrap().unwrap().value,
+            vec![0; 100]
+        );
+    }
+
+    #[test]
+    fn spills_large_values_into_overflow_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.db");
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, &path).unwrap();
+        let value = |key: u64, len: usize| -> Vec<u8> {
+            (0..len).map(|i| (i as u64 * 31 + key) as u8).collect()
+        };
+        // Values right at the cell limit stay inline, a byte more spills.
+        let sizes = [
+            0,
+            MAX_PAIR_SIZE - 8,
+            MAX_PAIR_SIZE - 7,
+            OVERFLOW_CAPACITY * 3,
+            100_000,
+        ];
+        for key in 0..200u64 {
+            let len = sizes[key as usize % sizes.len()];
+            btree.insert(&int_key(key), value(key, len)).unwrap();
+        }
+        btree.commit();
+        pager.flush().unwrap();
+        drop(btree);
+
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, &path).unwrap();
+        for pair in btree.range(..) {
+            let pair = pair.unwrap();
+            let key = from_int_key(&pair.key);
+            assert_eq!(pair.value, value(key, sizes[key as usize % sizes.len()]));
+        }
+
+        // Streaming reads the value in pieces without loading it whole.
+        let mut reader = btree.value_reader(&int_key(4)).unwrap().unwrap();
+        let mut piece = [0; 1000];
+        reader.read_exact(&mut piece).unwrap();
+        assert_eq!(piece[..], value(4, 1000)[..]);
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, value(4, 100_000)[1000..]);
+        assert!(btree.value_reader(&int_key(500)).unwrap().is_none());
+
+        // Replaced and removed values give their overflow pages back once committed and
+        // flushed, new values reuse them without growing the file.
+        for key in (0..200u64).filter(|key| key % 5 == 4) {
+            btree.insert(&int_key(key), vec![1]).unwrap();
+        }
+        for key in (0..200u64).filter(|key| key % 5 == 3) {
+            let removed = btree.remove(&int_key(key)).unwrap().unwrap();
+            assert_eq!(removed.value, value(key, OVERFLOW_CAPACITY * 3));
+        }
+        btree.commit();
+        pager.flush().unwrap();
+        let pages = pager.page_count(btree.file);
+        for key in 200..240u64 {
+            btree.insert(&int_key(key), value(key, 100_000)).unwrap();
+        }
+        assert_eq!(pager.page_count(btree.file), pages);
+    }
+
+    #[test]
+    fn test_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(1024 * 1024));
+        let btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
+
+        // Insert key-value pairs
+        btree
+            .insert(&int_key(1), "value1".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(2), "value2".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(3), "value3".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(4), "value4".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(5), "value5".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(6), "value6".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(7), "value7".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(8), "value8".as_bytes().to_vec())
+            .unwrap();
+
+        // Remove some values
+        assert_eq!(
+            btree.remove(&int_key(3)).unwrap(),
+            Some(KeyValuePair::new(int_key(3), "value3".as_bytes().to_vec()))
+        );
+        btree.remove(&int_key(4)).unwrap();
+        btree.remove(&int_key(8)).unwrap();
+        assert_eq!(btree.remove(&int_key(8)).unwrap(), None);
+
+        // Check the values were removed correctly
+        assert_eq!(btree.search(&int_key(3)).unwrap(), None);
+        assert_eq!(btree.search(&int_key(4)).unwrap(), None);
+        assert_eq!(btree.search(&int_key(8)).unwrap(), None);
+
+        // Check the values are still ordered correctly
+        let keys = stored_keys(&btree);
+        assert_eq!(keys, vec![1, 2, 5, 6, 7]);
+    }
+
+    #[test]
+    fn remove_merges_pages_and_shrinks_the_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
+        for key in 0..5000u64 {
+            btree.insert(&int_key(key), vec![key as u8; 40]).unwrap();
+        }
+        let pages = pager.page_count(btree.file);
+
+        // Removing every other key borrows between siblings, the rest empties the tree.
+        let keys: Vec<u64> = (0..5000).map(|i| (i * 7919) % 5000).collect();
+        for key in keys.iter().filter(|key| *key % 2 == 0) {
+            assert_eq!(
+                btree.remove(&int_key(*key)).unwrap().unwrap().key,
+                int_key(*key)
+            );
+        }
+        assert_eq!(
+            stored_keys(&btree),
+            (0..5000).filter(|k| k % 2 == 1).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            btree.search(&int_key(4001)).unwrap().unwrap().key,
+            int_key(4001)
+        );
+
+        for key in keys.iter().filter(|key| *key % 2 == 1) {
+            btree.remove(&int_key(*key)).unwrap();
+        }
+        assert_eq!(btree.read_node(btree.root()).unwrap(), Node::new_leaf());
+
+        // Freed pages are reused once committed and flushed, before the file grows.
+        btree.commit();
+        pager.flush().unwrap();
+        for key in 0..5000u64 {
+            btree.insert(&int_key(key), vec![key as u8; 40]).unwrap();
+        }
+        assert_eq!(pager.page_count(btree.file), pages);
+    }
+
+    #[test]
+    fn bulk_load_fills_pages_and_keeps_the_tree_usable() {
+        let dir = tempfile::tempdir().unwrap();
+        let value = |key: u64| {
+            let len = if key.is_multiple_of(1000) { 10_000 } else { 40 };
+            vec![key as u8; len]
+        };
+        let pairs = || (0..20_000u64).map(|key| (int_key(key), value(key)));
+
+        let pager = Arc::new(Pager::new(0));
+        let inserted = Btree::open(&pager, dir.path().join("inserted.db")).unwrap();
+        for (key, value) in pairs() {
+            inserted.insert(&key, value).unwrap();
+        }
+        let mut full = Btree::open(&pager, dir.path().join("full.db")).unwrap();
+        full.bulk_load(pairs(), 1.0).unwrap();
+        let mut sparse = Btree::open(&pager, dir.path().join("sparse.db")).unwrap();
+        sparse.bulk_load(pairs(), 0.5).unwrap();
+        let pages = |tree: &Btree| pager.page_count(tree.file);
+        assert!(
+            pages(&full) < pages(&inserted),
+            "{} >= {}",
+            pages(&full),
+            pages(&inserted)
+        );
+        assert!(pages(&sparse) > pages(&full));
+
+        for tree in [&mut full, &mut sparse] {
+            assert_eq!(stored_keys(tree), (0..20_000).collect::<Vec<_>>());
+            let rev: Vec<u64> = tree
+                .range(int_key(5000)..int_key(15_000))
+                .rev()
+                .map(|pair| from_int_key(&pair.unwrap().key))
+                .collect();
+            assert_eq!(rev, (5000..15_000).rev().collect::<Vec<_>>());
+            assert_eq!(
+                tree.search(&int_key(7000)).unwrap().unwrap().value,
+                value(7000)
+            );
+
+            // Filled pages split and drain like any others.
+            for key in (0..20_000u64).step_by(3) {
+                tree.insert(&int_key(key), vec![1; 60]).unwrap();
+            }
+            for key in (0..20_000u64).filter(|key| key % 3 == 1) {
+                tree.remove(&int_key(key)).unwrap();
+            }
+            let expected: Vec<u64> = (0..20_000).filter(|key| key % 3 != 1).collect();
+            assert_eq!(stored_keys(tree), expected);
+        }
+
+        let mut tree = Btree::open(&pager, dir.path().join("bad.db")).unwrap();
+        let unsorted = vec![(int_key(2), vec![]), (int_key(1), vec![])];
+        assert!(matches!(
+            tree.bulk_load(unsorted, 1.0),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(matches!(
+            full.bulk_load(pairs(), 1.0),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn snapshots_read_their_version_while_the_tree_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
+        for key in 0..3000u64 {
+            btree.insert(&int_key(key), vec![1; 40]).unwrap();
+        }
+        let empty = btree.snapshot();
+        btree.commit();
+
+        // A scan on another thread keeps seeing the first version while batches commit.
+        let first = btree.snapshot();
+        let scan = std::thread::spawn(move || {
+            for _ in 0..20 {
+                let pairs: Vec<KeyValuePair> = first.range(..).map(Result::unwrap).collect();
+                assert_eq!(pairs.len(), 3000);
+                assert!(pairs.iter().all(|pair| pair.value == [1; 40]));
+            }
+        });
+        for round in 0..20u64 {
+            for key in (0..6000u64).step_by(7) {
+                btree
+                    .insert(&int_key(key), vec![round as u8 + 2; 40])
+                    .unwrap();
+            }
+            for key in (round..3000).step_by(20) {
+                btree.remove(&int_key(key)).unwrap();
+            }
+            btree.commit();
+        }
+        scan.join().unwrap();
+        assert_eq!(empty.keys().count(), 0);
+        drop(empty);
+
+        // Uncommitted changes are not part of a snapshot and can be rolled back.
+        let committed = stored_keys(&btree);
+        let before = btree.snapshot();
+        btree.insert(&int_key(10_000), vec![]).unwrap();
+        btree.remove(&int_key(6)).unwrap();
+        assert!(btree.search(&int_key(10_000)).unwrap().is_some());
+        assert_eq!(stored_keys_of(&btree.snapshot()), committed);
+        btree.rollback();
+        assert_eq!(stored_keys(&btree), committed);
+
+        // Pages of old versions are only reused once no snapshot reads them.
+        for round in 0..3 {
+            for key in 0..3000u64 {
+                btree.insert(&int_key(key), vec![round; 40]).unwrap();
+            }
+            btree.commit();
+        }
+        assert_eq!(stored_keys_of(&before), committed);
+        drop(before);
+        pager.flush().unwrap();
+        let pages = pager.page_count(btree.file);
+        for round in 0..3 {
+            for key in 0..3000u64 {
+                btree.insert(&int_key(key), vec![round; 40]).unwrap();
+            }
+            btree.commit();
+            pager.flush().unwrap();
+        }
+        assert_eq!(pager.page_count(btree.file), pages);
+
+        let _open = btree.snapshot();
+        assert!(matches!(btree.vacuum(), Err(Error::SnapshotsOpen(1))));
+    }
+
+    #[test]
+    fn pages_of_open_snapshots_are_free_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.db");
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, &path).unwrap();
+        for key in 0..3000u64 {
+            btree.insert(&int_key(key), vec![0; 40]).unwrap();
+        }
+        btree.commit();
+        pager.flush().unwrap();
+        let snapshot = btree.snapshot();
+        for key in 0..3000u64 {
+            btree.insert(&int_key(key), vec![1; 40]).unwrap();
+        }
+        btree.commit();
+        pager.flush().unwrap();
+
+        // The process stops with the snapshot still open.
+        std::mem::forget(snapshot);
+        drop(btree);
+        let report = check_file(&path).unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert!(report.free_pages > 0);
+    }
+
+    #[test]
+    fn threads_insert_remove_and_search_at_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
+        for key in (0..32_000u64).step_by(2) {
+            btree.insert(&int_key(key), vec![key as u8; 40]).unwrap();
+        }
+        // Committing first makes the writers copy pages on their way down.
+        btree.commit();
+
+        let threads = 8;
+        std::thread::scope(|scope| {
+            let btree = &btree;
+            for thread in 0..threads {
+                scope.spawn(move || {
+                    for key in (0..16_000u64).skip(thread).step_by(threads) {
+                        btree.insert(&int_key(key * 2 + 1), vec![1; 40]).unwrap();
+                        if key % 2 == 0 {
+                            let removed = btree.remove(&int_key(key * 2)).unwrap();
+                            assert_eq!(removed.unwrap().value, vec![(key * 2) as u8; 40]);
+                        }
+                    }
+                });
+            }
+            // Keys that are 2 mod 4 stay put and must be seen throughout.
+            for _ in 0..2 {
+                scope.spawn(move || {
+                    for _ in 0..5 {
+                        for key in (2..32_000u64).step_by(396) {
+                            assert!(btree.search(&int_key(key)).unwrap().is_some());
+                        }
+                        let keys = stored_keys(btree);
+                        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+                        let kept = keys.iter().filter(|key| *key % 4 == 2).count();
+                        assert_eq!(kept, 8000);
+                    }
+                });
+            }
+        });
+
+        let expected: Vec<u64> = (0..32_000).filter(|key| key % 4 != 0).collect();
+        assert_eq!(stored_keys(&btree), expected);
+        btree.commit();
+        assert_eq!(stored_keys_of(&btree.snapshot()), expected);
+    }
+
+    #[test]
+    fn threads_growing_the_root_keep_every_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(0));
+        let btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
+        // Long keys keep the fan out low, so the root splits over and over while other
+        // writers are below it.
+        let long_key = |n: u64| [int_key(n), vec![0; 400]].concat();
+
+        let threads = 8;
+        std::thread::scope(|scope| {
+            let btree = &btree;
+            for thread in 0..threads {
+                scope.spawn(move || {
+                    for key in (0..40_000u64).skip(thread).step_by(threads) {
+                        btree.insert(&long_key(key), vec![1; 8]).unwrap();
+                    }
+                });
+            }
+        });
+
+        let keys: Vec<u64> = btree
+            .keys()
+            .map(|key| from_int_key(&key.unwrap()[..8]))
+            .collect();
+        assert_eq!(keys, (0..40_000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn index_trees_keep_keys_in_index_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.db");
+        let pager = Arc::new(Pager::new(0));
+        let mut index = Btree::open_index(&pager, &path).unwrap();
+        for key in 0..5000u64 {
+            index.insert(&int_key(key * 3), vec![]).unwrap();
+        }
+        assert!(matches!(
+            index.insert(&int_key(1), vec![1]),
+            Err(Error::InvalidInput(_))
+        ));
+        index.remove(&int_key(9)).unwrap();
+        index.commit();
+        pager.flush().unwrap();
+        drop(index);
+
+        let pager = Arc::new(Pager::new(0));
+        let index = Btree::open_index(&pager, &path).unwrap();
+        let root = pager.get_page(index.file, index.root()).unwrap();
+        assert_eq!(
+            root.header().unwrap().page_type,
+            BTreePageType::InteriorIndex
+        );
+        let Node::Internal { children, .. } = index.read_node(index.root()).unwrap() else {
+            unreachable!();
+        };
+        let leaf = pager.get_page(index.file, children[0]).unwrap();
+        assert_eq!(leaf.header().unwrap().page_type, BTreePageType::LeafIndex);
+
+        assert!(index.search(&int_key(6)).unwrap().is_some());
+        assert!(index.search(&int_key(9)).unwrap().is_none());
+        let keys: Vec<u64> = index
+            .prefix(&int_key(256)[..7])
+            .map(|pair| from_int_key(&pair.unwrap().key))
+            .collect();
+        assert_eq!(keys, (258..512).step_by(3).collect::<Vec<_>>());
+        drop(index);
+
+        // Neither kind of tree opens a file holding the other.
+        let pager = Arc::new(Pager::new(0));
+        assert!(Btree::open(&pager, &path).is_err());
+    }
+
+    #[test]
+    fn vacuum_moves_pages_and_truncates_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.db");
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, &path).unwrap();
+        for key in 0..3000u64 {
+            let len = if key % 100 == 0 { 20_000 } else { 40 };
+            btree.insert(&int_key(key), vec![key as u8; len]).unwrap();
+        }
+        // Removing the low keys frees pages near the start of the file.
+        for key in 0..2000u64 {
+            btree.remove(&int_key(key)).unwrap();
+        }
+        btree.commit();
+        pager.flush().unwrap();
+        let before = std::fs::metadata(&path).unwrap().len();
+
+        btree.vacuum().unwrap();
+        pager.flush().unwrap();
+        let after = std::fs::metadata(&path).unwrap().len();
+        assert!(after < before / 2, "{after} >= {before} / 2");
+        assert_eq!(
+            after,
+            pager.page_count(btree.file) as u64 * PAGE_SIZE as u64
+        );
+        assert!(pager.free_pages(btree.file).is_empty());
+        drop(btree);
+
+        let pager = Arc::new(Pager::new(0));
+        let btree = Btree::open(&pager, &path).unwrap();
+        assert_eq!(stored_keys(&btree), (2000..3000).collect::<Vec<_>>());
+        let rev: Vec<u64> = btree
+            .keys()
+            .rev()
+            .map(|key| from_int_key(&key.unwrap()))
+            .collect();
+        assert_eq!(rev, (2000..3000).rev().collect::<Vec<_>>());
+        for key in (2000..3000u64).step_by(100) {
+            let pair = btree.search(&int_key(key)).unwrap().unwrap();
+            assert_eq!(pair.value, vec![key as u8; 20_000]);
+        }
+    }
+
+    #[test]
+    fn upsert_resolves_existing_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(1024 * 1024));
+        let btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
+
+        assert_eq!(btree.insert(&int_key(1), b"a".to_vec()).unwrap(), None);
+        assert_eq!(
+            btree.insert(&int_key(1), b"b".to_vec()).unwrap(),
+            Some(KeyValuePair::new(int_key(1), b"a".to_vec()))
+        );
+        let previous = btree.upsert(&int_key(1), b"c".to_vec(), ConflictPolicy::Keep);
+        assert_eq!(previous.unwrap().unwrap().value, b"b");
+        assert_eq!(btree.search(&int_key(1)).unwrap().unwrap().value, b"b");
+
+        let concat = |old: &[u8], new: &[u8]| [old, new].concat();
+        btree
+            .upsert(&int_key(1), b"c".to_vec(), ConflictPolicy::Merge(&concat))
+            .unwrap();
+        assert_eq!(btree.search(&int_key(1)).unwrap().unwrap().value, b"bc");
+
+        // Values that grow in place split their leaf like new keys do.
+        for key in 0..200u64 {
+            btree.insert(&int_key(key), vec![1; 10]).unwrap();
+        }
+        for key in 0..200u64 {
+            btree
+                .upsert(&int_key(key), vec![2; 30], ConflictPolicy::Merge(&concat))
+                .unwrap();
+        }
+        let pairs: Vec<KeyValuePair> = btree.range(..).collect::<Result<_>>().unwrap();
+        assert_eq!(pairs.len(), 200);
+        assert!(pairs.iter().all(|pair| pair.value.len() == 40));
+    }
+
+    #[test]
+    fn test_iteration() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(1024 * 1024));
+        let btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
+
+        // Insert key-value pairs
+        btree
+            .insert(&int_key(1), "value1".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(2), "value2".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(3), "value3".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(4), "value4".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(5), "value5".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(6), "value6".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(7), "value7".as_bytes().to_vec())
+            .unwrap();
+        btree
+            .insert(&int_key(8), "value8".as_bytes().to_vec())
+            .unwrap();
+
+        // Check the values are iterated correctly
+        let values: Vec<Vec<u8>> = btree
+            .range(..=int_key(5))
+            .map(|pair| pair.unwrap().value)
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                "value1".as_bytes().to_vec(),
+                "value2".as_bytes().to_vec(),
+                "value3".as_bytes().to_vec(),
+                "value4".as_bytes().to_vec(),
+                "value5".as_bytes().to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn ranges_read_leaves_as_writers_left_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
+        for key in (0..6000u64).step_by(2) {
+            btree.insert(&int_key(key), vec![1; 40]).unwrap();
+        }
+        // Committing first makes the inserts below copy the nodes the scan has read.
+        btree.commit();
+
+        let mut seen = vec![];
+        for pair in btree.range(..) {
+            let key = from_int_key(&pair.unwrap().key);
+            if key == 1000 {
+                for odd in (3001..6000u64).step_by(2) {
+                    btree.insert(&int_key(odd), vec![2; 40]).unwrap();
+                }
+            }
+            seen.push(key);
+        }
+        let expected: Vec<u64> = (0..6000)
+            .filter(|key| key % 2 == 0 || *key > 3000)
+            .collect();
+        assert_eq!(seen, expected);
+
+        let mut seen = vec![];
+        for pair in btree.range(..).rev() {
+            let key = from_int_key(&pair.unwrap().key);
+            if key == 5000 {
+                for odd in (1..3000u64).step_by(2) {
+                    btree.insert(&int_key(odd), vec![2; 40]).unwrap();
+                }
+            }
+            seen.push(key);
+        }
+        let expected: Vec<u64> = (0..6000).rev().collect();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn range_scans_across_leaves_in_both_directions() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(0));
+        let btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
+        let keys: Vec<u64> = (0..3000).map(|i| (i * 7919) % 3000 * 2).collect();
+        for key in &keys {
+            btree
+                .insert(&int_key(*key), key.to_le_bytes().to_vec())
+                .unwrap();
+        }
+        // Thin out some leaves so scans also cross merged and rebalanced pages.
+        for key in (1000..2000).step_by(4) {
+            btree.remove(&int_key(key)).unwrap();
+        }
+        let stored: Vec<u64> = (0..6000)
+            .step_by(2)
+            .filter(|key| !(1000..2000).contains(key) || key % 4 != 0)
+            .collect();
+
+        let scan = |range: Range| -> Vec<u64> {
+            range
+                .map(|pair| {
+                    let pair = pair.unwrap();
+                    let key = from_int_key(&pair.key);
+                    assert_eq!(pair.value, key.to_le_bytes());
+                    key
+                })
+                .collect()
+        };
+        let expect = |range: std::ops::Range<u64>| -> Vec<u64> {
+            stored
+                .iter()
+                .copied()
+                .filter(|k| range.contains(k))
+                .collect()
+        };
+        assert_eq!(scan(btree.range(..)), stored);
+        assert_eq!(
+            scan(btree.range(int_key(901)..int_key(4501))),
+            expect(901..4501)
+        );
+        assert_eq!(
+            scan(btree.range(int_key(900)..=int_key(4500))),
+            expect(900..4501)
+        );
+
+        let mut reversed = expect(901..4501);
+        reversed.reverse();
+        let rev: Vec<u64> = btree
+            .range(int_key(901)..int_key(4501))
+            .rev()
+            .map(|pair| from_int_key(&pair.unwrap().key))
+            .collect();
+        assert_eq!(rev, reversed);
+        let mut all = stored.clone();
+        all.reverse();
+        let rev: Vec<u64> = btree
+            .keys()
+            .rev()
+            .map(|key| from_int_key(&key.unwrap()))
+            .collect();
+        assert_eq!(rev, all);
+        assert!(scan(btree.range(int_key(6000)..)).is_empty());
+
+        // Both ends of one iterator meet without yielding a key twice.
+        let mut range = btree.range(int_key(100)..int_key(110));
+        let mut seen = vec![];
+        while let (Some(front), back) = (range.next(), range.next_back()) {
+            seen.push(from_int_key(&front.unwrap().key));
+            seen.extend(back.map(|pair| from_int_key(&pair.unwrap().key)));
+        }
+        seen.sort();
+        assert_eq!(seen, vec![100, 102, 104, 106, 108]);
+    }
+
+    #[test]
+    fn composite_keys_group_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(0));
+        let btree = Btree::open(&pager, dir.path().join("tree.db")).unwrap();
+        let composite = |series: &[u8], time: i64| {
+            let mut encoder = ByteEncoder::new(vec![]);
+            encoder.write_ordered_bytes(series).unwrap();
+            encoder.write_ordered_i64(time).unwrap();
+            encoder.inner
+        };
+        let series: [&[u8]; 4] = [b"cpu", b"cpu\0host", b"cpux", b"\xff\xff"];
+        for name in series {
+            for time in -300..300 {
+                btree.insert(&composite(name, time), vec![]).unwrap();
+            }
+        }
+
+        for name in series {
+            let mut prefix = ByteEncoder::new(vec![]);
+            prefix.write_ordered_bytes(name).unwrap();
+            let times: Vec<i64> = btree
+                .prefix(&prefix.inner)
+                .map(|pair| {
+                    let pair = pair.unwrap();
+                    let mut decoder = ByteDecoder::new(&pair.key[..]);
+                    assert_eq!(decoder.read_ordered_bytes().unwrap(), name);
+                    decoder.read_ordered_i64().unwrap()
+                })
+                .collect();
+            assert_eq!(times, (-300..300).collect::<Vec<_>>());
+        }
+        assert_eq!(btree.prefix(b"cpu").count(), 1800);
+        assert_eq!(btree.prefix(&[0xff]).rev().count(), 600);
+        assert_eq!(btree.prefix(b"mem").count(), 0);
+    }
 }
@@ -0,0 +1,440 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::errors::{Error, Result};
+
+use super::b_tree::{BTreePageType, LeafValue, Node, TreeKind, MAX_KEY_SIZE};
+use super::paging::{Page, OVERFLOW_CAPACITY};
+use super::Storage;
+
+/// What is wrong with a page found by `check_file`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    /// The page content does not match its checksum.
+    Checksum,
+    /// The page cannot be read as what refers to it, or is past the end of the file.
+    Unreadable,
+    /// The keys of a node are not strictly ascending.
+    KeyOrder,
+    /// A key lies outside the separators its parent routes to the page.
+    Separator,
+    /// The first key of a leaf is not above the last key of the leaf before it. Leaves
+    /// have no sibling links, see `Node`, so this checks neighbouring leaves line up
+    /// where a sibling link check would.
+    LeafOrder,
+    /// A node does not fit its page, holds a key that is too large, or is empty below
+    /// the root.
+    Fill,
+    /// A leaf is at another depth than the first leaf.
+    Depth,
+    /// An overflow chain is shorter or longer than its value.
+    Overflow,
+    /// The page is referred to more than once.
+    SharedPage,
+    /// The page is in the free list twice, or both free and in use.
+    Free,
+    /// The page is neither reachable from the root nor free, so it has leaked.
+    Unreachable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Issue {
+    pub page: u32,
+    pub problem: Problem,
+    pub detail: String,
+}
+
+/// Result of checking a tree file, the output of `solipsist-db check`.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// Kind of tree the root page holds, `None` when the root cannot be read.
+    pub kind: Option<TreeKind>,
+    pub version: String,
+    pub pages: u32,
+    pub free_pages: usize,
+    /// Levels from the root down to the leaves.
+    pub depth: usize,
+    pub nodes: usize,
+    pub leaves: usize,
+    pub keys: usize,
+    pub overflow_pages: usize,
+    /// Nodes below the root less than a quarter full. Merges leave none, but bulk loads
+    /// with a low fill factor do on purpose, so they are counted rather than reported.
+    pub underfull: usize,
+    /// Problems found, by page.
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Checks the tree file at `path` as it was last flushed, without writing to it. Every
+/// page reachable from the root is read and verified, then every other page has to be
+/// in the free list. Only a file that cannot be opened at all is an error, whatever is
+/// wrong inside it ends up in the report.
+pub fn check_file<P: AsRef<Path>>(path: P) -> Result<Report> {
+    let storage = Storage::open_read_only(path)?;
+    let mut checker = Checker {
+        storage: &storage,
+        kind: TreeKind::Table,
+        used: BTreeSet::new(),
+        last_leaf: None,
+        report: Report {
+            version: storage.version().to_string(),
+            pages: storage.page_count(),
+            free_pages: storage.free_pages().len(),
+            ..Report::default()
+        },
+    };
+    checker.check();
+    let mut report = checker.report;
+    report.issues.sort_by_key(|issue| issue.page);
+    Ok(report)
+}
+
+struct Checker<'a> {
+    storage: &'a Storage,
+    kind: TreeKind,
+    /// Pages reached from the root so far.
+    used: BTreeSet<u32>,
+    /// Page and last key of the leaf visited last, leaves are visited in key order.
+    last_leaf: Option<(u32, Vec<u8>)>,
+    report: Report,
+}
+
+impl Checker<'_> {
+    fn check(&mut self) {
+        let root = self.storage.root();
+        if root == 0 {
+            self.issue(0, Problem::Unreadable, "the file holds no tree".to_owned());
+            return;
+        }
+        let Some(page) = self.read(root) else {
+            // Every other page would be reported unreachable.
+            return;
+        };
+        self.kind = match page.header().map(|header| header.page_type) {
+            Ok(BTreePageType::LeafTable | BTreePageType::InteriorTable) => TreeKind::Table,
+            Ok(BTreePageType::LeafIndex | BTreePageType::InteriorIndex) => TreeKind::Index,
+            Ok(page_type) => {
+                let detail = format!("the root is a {page_type:?} page");
+                return self.issue(root, Problem::Unreadable, detail);
+            }
+            Err(err) => return self.issue(root, Problem::Unreadable, err.to_string()),
+        };
+        self.report.kind = Some(self.kind);
+        self.visit(root, 1, None, None);
+
+        let mut free = BTreeSet::new();
+        for page in self.storage.free_pages() {
+            if page == 0 || page >= self.report.pages {
+                let detail = "a free page is past the end of the file".to_owned();
+                self.issue(page, Problem::Free, detail);
+            } else if !free.insert(page) {
+                self.issue(page, Problem::Free, "listed as free twice".to_owned());
+            } else if self.used.contains(&page) {
+                self.issue(page, Problem::Free, "free but in use".to_owned());
+            }
+        }
+        for page in 1..self.report.pages {
+            if !self.used.contains(&page) && !free.contains(&page) {
+                let detail = "neither in use nor free".to_owned();
+                self.issue(page, Problem::Unreachable, detail);
+            }
+        }
+    }
+
+    fn issue(&mut self, page: u32, problem: Problem, detail: String) {
+        self.report.issues.push(Issue {
+            page,
+            problem,
+            detail,
+        });
+    }
+
+    /// Records that `page` is referred to, `false` if it was already.
+    fn reach(&mut self, page: u32) -> bool {
+        if !self.used.insert(page) {
+            let detail = "referred to more than once".to_owned();
+            self.issue(page, Problem::SharedPage, detail);
+            return false;
+        }
+        true
+    }
+
+    fn read(&mut self, number: u32) -> Option<Page> {
+        match self.storage.read_page(number).map_err(Error::from) {
+            Ok(page) => Some(page),
+            Err(err @ Error::CorruptPage(_)) => {
+                self.issue(number, Problem::Checksum, err.to_string());
+                None
+            }
+            Err(err) => {
+                self.issue(number, Problem::Unreadable, err.to_string());
+                None
+            }
+        }
+    }
+
+    /// Checks the sub tree rooted at `number`, whose keys have to be above `lower` and
+    /// up to `upper`.
+    fn visit(&mut self, number: u32, depth: usize, lower: Option<&[u8]>, upper: Option<&[u8]>) {
+        if !self.reach(number) {
+            return;
+        }
+        let Some(page) = self.read(number) else {
+            return;
+        };
+        let node = match Node::from_page(&page, self.kind) {
+            Ok(node) => node,
+            Err(err) => return self.issue(number, Problem::Unreadable, err.to_string()),
+        };
+        self.report.nodes += 1;
+        let is_root = depth == 1;
+        let keys = match &node {
+            Node::Internal { keys, .. } | Node::Leaf { keys, .. } => keys,
+        };
+
+        if let Some(idx) = keys.windows(2).position(|pair| pair[0] >= pair[1]) {
+            let detail = format!("key {} is not above the key before it", idx + 1);
+            self.issue(number, Problem::KeyOrder, detail);
+        }
+        let outside = keys.iter().position(|key| {
+            lower.is_some_and(|lower| key.as_slice() <= lower)
+                || upper.is_some_and(|upper| key.as_slice() > upper)
+        });
+        if let Some(idx) = outside {
+            let detail = format!("key {idx} is outside the separators of the parent");
+            self.issue(number, Problem::Separator, detail);
+        }
+        if node.is_full() {
+            self.issue(
+                number,
+                Problem::Fill,
+                "the node overfills its page".to_owned(),
+            );
+        }
+        if let Some(key) = keys.iter().find(|key| key.len() > MAX_KEY_SIZE) {
+            let detail = format!("a key of {} bytes is over the limit", key.len());
+            self.issue(number, Problem::Fill, detail);
+        }
+        if !is_root {
+            if keys.is_empty() {
+                self.issue(number, Problem::Fill, "empty below the root".to_owned());
+            }
+            if node.is_underflow() {
+                self.report.underfull += 1;
+            }
+        }
+
+        match node {
+            Node::Internal { keys, children } => {
+                for (idx, child) in children.iter().enumerate() {
+                    let lower = match idx {
+                        0 => lower,
+                        idx => Some(keys[idx - 1].as_slice()),
+                    };
+                    let upper = keys.get(idx).map(Vec::as_slice).or(upper);
+                    self.visit(*child, depth + 1, lower, upper);
+                }
+            }
+            Node::Leaf { keys, values } => {
+                self.report.leaves += 1;
+                self.report.keys += keys.len();
+                if self.report.depth == 0 {
+                    self.report.depth = depth;
+                } else if depth != self.report.depth {
+                    let detail = format!(
+                        "leaf at depth {depth}, the first leaf is at {}",
+                        self.report.depth
+                    );
+                    self.issue(number, Problem::Depth, detail);
+                }
+                if let (Some((previous, last)), Some(first)) = (&self.last_leaf, keys.first()) {
+                    if first <= last {
+                        let detail =
+                            format!("the first key is not above the last key of leaf {previous}");
+                        self.issue(number, Problem::LeafOrder, detail);
+                    }
+                }
+                if let Some(last) = keys.last() {
+                    self.last_leaf = Some((number, last.clone()));
+                }
+                for value in values {
+                    if let LeafValue::Overflow { len, first } = value {
+                        self.check_chain(number, len, first);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks the overflow chain starting at `first` holds a value of `len` bytes for
+    /// the leaf `leaf`.
+    fn check_chain(&mut self, leaf: u32, len: u32, first: u32) {
+        let mut remaining = len as usize;
+        let mut number = first;
+        while remaining > 0 {
+            if number == 0 {
+                let detail = format!("a chain ends {remaining} bytes short of its value");
+                return self.issue(leaf, Problem::Overflow, detail);
+            }
+            if !self.reach(number) {
+                return;
+            }
+            let Some(page) = self.read(number) else {
+                return;
+            };
+            let header = match page.header() {
+                Ok(header) if header.page_type == BTreePageType::Overflow => header,
+                Ok(header) => {
+                    let page_type = header.page_type;
+                    let detail = format!("expected an overflow page, found {page_type:?}");
+                    return self.issue(number, Problem::Unreadable, detail);
+                }
+                Err(err) => return self.issue(number, Problem::Unreadable, err.to_string()),
+            };
+            self.report.overflow_pages += 1;
+            remaining = remaining.saturating_sub(OVERFLOW_CAPACITY);
+            number = header.right_pointer;
+        }
+        if number != 0 {
+            let detail = format!("the chain goes on to page {number} past its value");
+            self.issue(leaf, Problem::Overflow, detail);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::b_tree::Btree;
+    use crate::storage::pager::Pager;
+    use crate::storage::paging::PAGE_SIZE;
+
+    fn problems(report: &Report) -> Vec<(u32, Problem)> {
+        report
+            .issues
+            .iter()
+            .map(|issue| (issue.page, issue.problem))
+            .collect()
+    }
+
+    /// Writes the leaf at `page` back with its keys changed by `edit`.
+    fn rewrite_leaf(storage: &Storage, page: u32, edit: impl FnOnce(&mut Vec<Vec<u8>>)) {
+        let mut node = Node::from_page(&storage.read_page(page).unwrap(), TreeKind::Table).unwrap();
+        let Node::Leaf { keys, .. } = &mut node else {
+            unreachable!();
+        };
+        edit(keys);
+        let page = node.to_page(page, TreeKind::Table).unwrap();
+        storage.write_page(&page).unwrap();
+    }
+
+    #[test]
+    fn passes_trees_after_inserts_removes_and_bulk_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Arc::new(Pager::new(0));
+        let mut table = Btree::open(&pager, dir.path().join("table.db")).unwrap();
+        for key in 0..3000u32 {
+            let len = if key % 100 == 0 { 10_000 } else { 40 };
+            table
+                .insert(&key.to_be_bytes(), vec![key as u8; len])
+                .unwrap();
+        }
+        for key in (0..3000u32).step_by(3) {
+            table.remove(&key.to_be_bytes()).unwrap();
+        }
+        table.commit();
+        let mut index = Btree::open_index(&pager, dir.path().join("index.db")).unwrap();
+        let keys = (0..5000u32).map(|key| (key.to_be_bytes().to_vec(), vec![]));
+        index.bulk_load(keys, 0.2).unwrap();
+        index.commit();
+        pager.flush().unwrap();
+
+        let report = check_file(dir.path().join("table.db")).unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(report.kind, Some(TreeKind::Table));
+        assert_eq!(report.keys, 2000);
+        assert!(report.depth >= 2);
+        assert!(report.overflow_pages > 0);
+
+        let report = check_file(dir.path().join("index.db")).unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(report.kind, Some(TreeKind::Index));
+        assert_eq!(report.keys, 5000);
+        assert!(report.underfull > 0);
+    }
+
+    #[test]
+    fn reports_problems_by_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.db");
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, &path).unwrap();
+        for key in 0..1000u32 {
+            btree
+                .insert(&key.to_be_bytes(), vec![key as u8; 40])
+                .unwrap();
+        }
+        btree.commit();
+        pager.flush().unwrap();
+        drop(btree);
+        assert!(check_file(&path).unwrap().is_ok());
+
+        let mut storage = Storage::open(&path).unwrap();
+        let root = storage.read_page(storage.root()).unwrap();
+        let Node::Internal { keys, children } = Node::from_page(&root, TreeKind::Table).unwrap()
+        else {
+            unreachable!();
+        };
+        // Swap the first keys of the first leaf.
+        rewrite_leaf(&storage, children[0], |keys| keys.swap(0, 1));
+        // Move the last key of the second leaf past the separator after it.
+        let mut above = keys[1].clone();
+        above.push(0);
+        rewrite_leaf(&storage, children[1], |keys| {
+            *keys.last_mut().unwrap() = above
+        });
+        // Swap the fourth and fifth leaves in the root.
+        let mut swapped = children.clone();
+        swapped.swap(3, 4);
+        let node = Node::Internal {
+            keys: keys.clone(),
+            children: swapped,
+        };
+        let page = node.to_page(storage.root(), TreeKind::Table).unwrap();
+        storage.write_page(&page).unwrap();
+        // Leak a page.
+        let leaked = storage.allocate_page();
+        storage.write_page(&Page::new(leaked)).unwrap();
+        storage.write_meta().unwrap();
+        drop(storage);
+        // Flip a bit in the third leaf.
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[children[2] as usize * PAGE_SIZE + PAGE_SIZE / 2] ^= 0x10;
+        std::fs::write(&path, bytes).unwrap();
+
+        let report = check_file(&path).unwrap();
+        let mut expected = vec![
+            (children[0], Problem::KeyOrder),
+            (children[1], Problem::Separator),
+            (children[2], Problem::Checksum),
+            (children[3], Problem::Separator),
+            (children[3], Problem::LeafOrder),
+            (children[4], Problem::Separator),
+            (leaked, Problem::Unreachable),
+        ];
+        expected.sort_by_key(|(page, _)| *page);
+        assert_eq!(problems(&report), expected);
+        assert_eq!(report.leaves, children.len() - 1);
+    }
+}
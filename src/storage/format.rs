@@ -0,0 +1,301 @@
+use std::fmt;
+use std::io;
+
+use crate::byte_encoder::{ByteDecoder, ByteEncoder};
+
+use super::paging::{Page, PAGE_SIZE, USABLE_SIZE};
+use super::{invalid_data, Storage};
+
+/// Identifies a solipsist storage file, at the start of its meta page.
+const MAGIC: [u8; 8] = *b"SOLIPSDB";
+
+/// Bytes taken by `FileHeader` at the start of the meta page.
+pub const FILE_HEADER_SIZE: usize = 8 + 4 + 2 + 2;
+
+/// Format written by this build. A file with another major version has to be migrated
+/// before it is read, newer minor versions only add what older builds can ignore.
+pub const FORMAT_VERSION: Version = Version { major: 1, minor: 0 };
+
+/// Files written before the header existed.
+const HEADERLESS: Version = Version { major: 0, minor: 0 };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// A file written by a newer build with a major version this one cannot read. It travels
+/// inside an `InvalidData` I/O error and surfaces as `Error::UnsupportedVersion`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedVersion(pub Version);
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "format version {} is newer than the supported {}.x",
+            self.0, FORMAT_VERSION.major
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+/// Start of the meta page: the magic, the page size and the format version.
+pub struct FileHeader {
+    pub page_size: u32,
+    pub version: Version,
+}
+
+impl FileHeader {
+    /// Reads the header of a meta page, `None` for a file from before headers.
+    pub fn read(meta: &Page) -> io::Result<Option<FileHeader>> {
+        if meta.data[..MAGIC.len()] != MAGIC {
+            return Ok(None);
+        }
+        let mut reader = ByteDecoder::new(&meta.data[MAGIC.len()..FILE_HEADER_SIZE]);
+        Ok(Some(FileHeader {
+            page_size: reader.read_u32()?,
+            version: Version {
+                major: reader.read_u16()?,
+                minor: reader.read_u16()?,
+            },
+        }))
+    }
+
+    pub fn write(&self, meta: &mut Page) -> io::Result<()> {
+        let mut writer = ByteEncoder::new(&mut meta.data[..FILE_HEADER_SIZE]);
+        writer.write_bytes(&MAGIC)?;
+        writer.write_u32(self.page_size)?;
+        writer.write_u16(self.version.major)?;
+        writer.write_u16(self.version.minor)
+    }
+}
+
+/// Upgrades a file by one major version in place, `MIGRATIONS[n]` takes a file at major
+/// version `n` with meta page `meta` to version `n + 1`.
+type Migration = fn(&mut Storage, &Page) -> io::Result<()>;
+
+const MIGRATIONS: [Migration; FORMAT_VERSION.major as usize] = [add_file_header];
+
+/// Headerless files start their meta page with the root and the free list, which now
+/// follow the header. The free list is read with the capacity it had then, and written
+/// to trunks outside the old chain, so the old meta page reads as before until the new
+/// one is synced.
+fn add_file_header(storage: &mut Storage, meta: &Page) -> io::Result<()> {
+    storage.read_meta_fields(&meta.data[..USABLE_SIZE], (USABLE_SIZE - 12) / 4)?;
+    storage.version = FORMAT_VERSION;
+    storage.write_meta()
+}
+
+/// Checks the header of an opened file and migrates it to `FORMAT_VERSION`, returning
+/// the version it is at afterwards. Each migration is synced before the next one runs,
+/// so an interrupted upgrade picks up where it stopped.
+pub(super) fn upgrade(storage: &mut Storage) -> io::Result<Version> {
+    loop {
+        let meta = storage.read_page(0)?;
+        let version = read_version(&meta)?;
+        if version.major == FORMAT_VERSION.major {
+            return Ok(version);
+        }
+        MIGRATIONS[version.major as usize](storage, &meta)?;
+    }
+}
+
+/// Version of the file with meta page `meta`, refusing other page sizes and major
+/// versions newer than this build.
+pub(super) fn read_version(meta: &Page) -> io::Result<Version> {
+    let version = match FileHeader::read(meta)? {
+        Some(header) if header.page_size as usize != PAGE_SIZE => {
+            return Err(invalid_data(format!(
+                "file has {} byte pages, expected {PAGE_SIZE}",
+                header.page_size
+            )));
+        }
+        Some(header) => header.version,
+        None => HEADERLESS,
+    };
+    if version.major > FORMAT_VERSION.major {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            UnsupportedVersion(version),
+        ));
+    }
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::errors::Error;
+    use crate::storage::b_tree::Btree;
+    use crate::storage::check::check_file;
+    use crate::storage::pager::Pager;
+    use crate::storage::paging::PAGE_HEADER_SIZE;
+    use crate::storage::META_FREE_CAPACITY;
+
+    /// Rewrites the meta page of a flushed file with `edit`.
+    fn edit_meta(path: &Path, edit: impl FnOnce(&mut Page)) {
+        let storage = Storage::open(path).unwrap();
+        let mut meta = storage.read_page(0).unwrap();
+        edit(&mut meta);
+        storage.write_page(&meta).unwrap();
+    }
+
+    #[test]
+    fn migrates_headerless_files_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.db");
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, &path).unwrap();
+        for key in 0..2000u32 {
+            btree.insert(&key.to_be_bytes(), vec![7; 40]).unwrap();
+        }
+        for key in 0..1000u32 {
+            btree.remove(&key.to_be_bytes()).unwrap();
+        }
+        btree.commit();
+        pager.flush().unwrap();
+        let free = pager.free_pages(0);
+        assert!(!free.is_empty());
+        drop(btree);
+
+        // Lay the meta page out the way it was before the header.
+        edit_meta(&path, |meta| {
+            meta.data.copy_within(FILE_HEADER_SIZE..USABLE_SIZE, 0);
+            meta.data[USABLE_SIZE - FILE_HEADER_SIZE..USABLE_SIZE].fill(0);
+        });
+
+        let pager = Arc::new(Pager::new(0));
+        let btree = Btree::open(&pager, &path).unwrap();
+        assert_eq!(pager.version(0), FORMAT_VERSION);
+        assert_eq!(pager.free_pages(0), free);
+        assert_eq!(btree.keys().count(), 1000);
+        let meta = Storage::open(&path).unwrap().read_page(0).unwrap();
+        assert_eq!(
+            FileHeader::read(&meta).unwrap().unwrap().version,
+            FORMAT_VERSION
+        );
+    }
+
+    #[test]
+    fn upgrades_cut_short_start_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.db");
+        let pager = Arc::new(Pager::new(0));
+        let mut btree = Btree::open(&pager, &path).unwrap();
+        // Values spilling into overflow pages free plenty of pages on removal.
+        for key in 0..600u32 {
+            btree.insert(&key.to_be_bytes(), vec![7; 10_000]).unwrap();
+        }
+        btree.commit();
+        pager.flush().unwrap();
+        for key in 0..500u32 {
+            btree.remove(&key.to_be_bytes()).unwrap();
+        }
+        btree.commit();
+        pager.flush().unwrap();
+        let mut free = pager.free_pages(0);
+        free.sort_unstable();
+        // Enough free pages to need trunks in either layout.
+        assert!(free.len() > META_FREE_CAPACITY + 16);
+        drop(btree);
+        // Lay the meta page out the way it was before the header, with the four more
+        // free pages it had room for then taken from the first trunk.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let storage = Storage::from_file(file).unwrap();
+        let mut meta = storage.read_page(0).unwrap();
+        meta.data.copy_within(FILE_HEADER_SIZE..USABLE_SIZE, 0);
+        let mut fields = ByteDecoder::new(&meta.data[4..12]);
+        let count = fields.read_u32().unwrap() as usize;
+        let first = fields.read_u32().unwrap();
+        assert_eq!(count, META_FREE_CAPACITY);
+        let mut trunk = storage.read_page(first).unwrap();
+        let mut header = trunk.header().unwrap();
+        let listed = 12 + count * 4;
+        meta.data[listed..USABLE_SIZE]
+            .copy_from_slice(&trunk.data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 16]);
+        let mut writer = ByteEncoder::new(&mut meta.data[4..8]);
+        writer.write_u32(count as u32 + 4).unwrap();
+        trunk
+            .data
+            .copy_within(PAGE_HEADER_SIZE + 16..USABLE_SIZE, PAGE_HEADER_SIZE);
+        header.n_cells -= 4;
+        trunk.data[..PAGE_HEADER_SIZE].copy_from_slice(&header.to_bytes().unwrap());
+        storage.write_page(&meta).unwrap();
+        storage.write_page(&trunk).unwrap();
+        drop(storage);
+
+        // The upgrade stops once the trunks for the new meta page are written.
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut storage = Storage::from_file(file).unwrap();
+        let meta = storage.read_page(0).unwrap();
+        storage
+            .read_meta_fields(&meta.data[..USABLE_SIZE], (USABLE_SIZE - 12) / 4)
+            .unwrap();
+        storage.write_free_list().unwrap();
+        storage.sync().unwrap();
+        drop(storage);
+
+        let pager = Arc::new(Pager::new(0));
+        let btree = Btree::open(&pager, &path).unwrap();
+        let mut reopened = pager.free_pages(0);
+        reopened.sort_unstable();
+        assert_eq!(reopened, free);
+        assert_eq!(btree.keys().count(), 100);
+        drop(btree);
+        assert!(check_file(&path).unwrap().is_ok());
+    }
+
+    #[test]
+    fn refuses_newer_major_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.db");
+        Storage::open(&path).unwrap();
+        let newer = |version| {
+            move |meta: &mut Page| {
+                let header = FileHeader {
+                    page_size: PAGE_SIZE as u32,
+                    version,
+                };
+                header.write(meta).unwrap();
+            }
+        };
+
+        edit_meta(&path, newer(Version { major: 1, minor: 3 }));
+        let mut storage = Storage::open(&path).unwrap();
+        assert_eq!(storage.version(), Version { major: 1, minor: 3 });
+        // Writing the meta page keeps the newer minor version.
+        storage.write_meta().unwrap();
+        drop(storage);
+        let storage = Storage::open(&path).unwrap();
+        assert_eq!(storage.version(), Version { major: 1, minor: 3 });
+        drop(storage);
+
+        edit_meta(&path, newer(Version { major: 2, minor: 0 }));
+        let pager = Arc::new(Pager::new(0));
+        assert!(matches!(
+            Btree::open(&pager, &path),
+            Err(Error::UnsupportedVersion { major: 2, minor: 0 })
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// Latch on the root pointer of a tree. Page 0 is the meta page, never a node.
+pub const ROOT_LATCH: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Held by any number of readers at once.
+    Shared,
+    /// Held by one writer, with no readers.
+    Exclusive,
+}
+
+#[derive(Default)]
+struct State {
+    readers: usize,
+    writer: bool,
+}
+
+/// Read and write latches on the pages of one tree. Unlike page pins they guard the
+/// structure of the tree: a thread moving from a node to its child latches the child
+/// before letting go of the node, so no split or merge can move keys between the two
+/// under it. Latches are only ever taken from the root down, which keeps them free of
+/// deadlocks.
+#[derive(Default)]
+pub struct Latches {
+    held: Mutex<HashMap<u32, State>>,
+    released: Condvar,
+}
+
+impl Latches {
+    /// Waits until `page` can be latched in `mode` and latches it.
+    pub fn acquire(&self, page: u32, mode: Mode) -> Latch<'_> {
+        let mut held = self.held.lock().unwrap();
+        loop {
+            let state = held.entry(page).or_default();
+            let free = match mode {
+                Mode::Shared => !state.writer,
+                Mode::Exclusive => !state.writer && state.readers == 0,
+            };
+            if free {
+                match mode {
+                    Mode::Shared => state.readers += 1,
+                    Mode::Exclusive => state.writer = true,
+                }
+                return Latch {
+                    latches: self,
+                    page,
+                    mode,
+                };
+            }
+            held = self.released.wait(held).unwrap();
+        }
+    }
+
+    pub fn shared(&self, page: u32) -> Latch<'_> {
+        self.acquire(page, Mode::Shared)
+    }
+
+    pub fn exclusive(&self, page: u32) -> Latch<'_> {
+        self.acquire(page, Mode::Exclusive)
+    }
+}
+
+/// A latch on a page, released when dropped.
+pub struct Latch<'a> {
+    latches: &'a Latches,
+    page: u32,
+    mode: Mode,
+}
+
+impl Latch<'_> {
+    pub fn page(&self) -> u32 {
+        self.page
+    }
+}
+
+impl Drop for Latch<'_> {
+    fn drop(&mut self) {
+        let mut held = self.latches.held.lock().unwrap();
+        let state = held.get_mut(&self.page).unwrap();
+        match self.mode {
+            Mode::Shared => state.readers -= 1,
+            Mode::Exclusive => state.writer = false,
+        }
+        if state.readers == 0 && !state.writer {
+            held.remove(&self.page);
+        }
+        self.latches.released.notify_all();
+    }
+}
+
+/// Counts the writers that started and finished changing a tree. A reader that saw no
+/// writer busy, and none starting since, knows the nodes it read are still current
+/// without holding latches on them.
+#[derive(Default)]
+pub struct Writes {
+    started: AtomicU64,
+    finished: AtomicU64,
+}
+
+impl Writes {
+    /// Counts a writer as busy until the returned guard is dropped.
+    pub fn begin(&self) -> Writing<'_> {
+        self.started.fetch_add(1, Ordering::SeqCst);
+        Writing(self)
+    }
+
+    /// The number of writers so far, if none of them is busy.
+    pub fn quiet(&self) -> Option<u64> {
+        let started = self.started.load(Ordering::SeqCst);
+        (self.finished.load(Ordering::SeqCst) == started).then_some(started)
+    }
+
+    /// Whether no writer started since `quiet` returned `count`.
+    pub fn unchanged_since(&self, count: u64) -> bool {
+        self.started.load(Ordering::SeqCst) == count
+    }
+}
+
+/// A busy writer, see `Writes::begin`.
+pub struct Writing<'a>(&'a Writes);
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        self.0.finished.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn exclusive_latches_wait_for_readers() {
+        let latches = Latches::default();
+        let written = AtomicBool::new(false);
+        let first = latches.shared(3);
+        let second = latches.shared(3);
+        // Other pages are not held up.
+        drop(latches.exclusive(4));
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _latch = latches.exclusive(3);
+                written.store(true, Ordering::SeqCst);
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(!written.load(Ordering::SeqCst));
+            drop(first);
+            thread::sleep(Duration::from_millis(50));
+            assert!(!written.load(Ordering::SeqCst));
+            drop(second);
+        });
+        assert!(written.load(Ordering::SeqCst));
+        assert!(latches.held.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn writes_are_quiet_only_between_writers() {
+        let writes = Writes::default();
+        assert_eq!(writes.quiet(), Some(0));
+        let writing = writes.begin();
+        assert_eq!(writes.quiet(), None);
+        assert!(!writes.unchanged_since(0));
+        drop(writing);
+        assert_eq!(writes.quiet(), Some(1));
+        assert!(writes.unchanged_since(1));
+    }
+}
@@ -1,9 +1,340 @@
-mod b_tree;
-mod paging;
-use std::fs::File;
+pub mod b_tree;
+pub mod check;
+pub mod format;
+pub mod latch;
+pub mod pager;
+pub mod paging;
 
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::byte_encoder::{ByteDecoder, ByteEncoder};
+
+use b_tree::BTreePageType;
+use format::{FileHeader, Version, FILE_HEADER_SIZE, FORMAT_VERSION};
+use paging::{Page, PageHeader, PAGE_HEADER_SIZE, PAGE_SIZE, USABLE_SIZE};
+
+/// Free page numbers held by the meta page after the file header, the root, the list
+/// length and the first trunk page.
+const META_FREE_CAPACITY: usize = (USABLE_SIZE - FILE_HEADER_SIZE - 12) / 4;
+
+/// Free page numbers held by a free list trunk page after its header.
+const TRUNK_CAPACITY: usize = (USABLE_SIZE - PAGE_HEADER_SIZE) / 4;
+
+pub(crate) fn invalid_data(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// A page read back with content that does not match its checksum. It travels inside
+/// an `InvalidData` I/O error and surfaces as `Error::CorruptPage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptPage {
+    pub page: u32,
+}
+
+impl fmt::Display for CorruptPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page {} failed its checksum", self.page)
+    }
+}
+
+impl std::error::Error for CorruptPage {}
+
+/// A file of `PAGE_SIZE` pages. Page 0 is the meta page holding the file header, the
+/// root page number and the start of the free list, B-tree pages follow it. Reads and writes go straight
+/// to the file, caching is left to the `Pager` in front of it.
+///
+/// Free page numbers that do not fit in the meta page are kept in trunk pages, free
+/// pages themselves chained from the meta page, so the list costs no space of its own.
+/// No page the meta page on disk refers to, as part of the tree or as a trunk, is
+/// written until a new meta page is synced, so a crash leaves the file as of the last
+/// one.
 pub(crate) struct Storage {
-    current_file: File,
+    current_file: Mutex<File>,
+    root: u32,
+    page_count: u32,
+    /// Pages the file on disk does not use, reused before the file grows.
+    free: Vec<u32>,
+    /// Pages the file on disk may still use: ones given back since the meta page was
+    /// last synced, and the trunks that meta page chains its free list through. The next
+    /// meta page lists them as free, and they are reused once it is synced.
+    held: Vec<u32>,
+    /// Pages that left the tree while a snapshot may still read them. No snapshot
+    /// outlives the process, so the meta page lists them as free, but they are only
+    /// reused once given back.
+    retired: BTreeSet<u32>,
+    /// Trunks of the next meta page, picked by `write_free_list`.
+    trunks: Vec<u32>,
+    /// Format version in the file header, kept when the file has a newer minor version.
+    version: Version,
 }
 
-impl Storage {}
+impl Storage {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Storage> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut storage = Storage::from_file(file)?;
+        if storage.page_count == 0 {
+            storage.page_count = 1;
+            storage.write_meta()?;
+        } else {
+            storage.version = format::upgrade(&mut storage)?;
+            let meta = storage.read_page(0)?;
+            storage.read_meta_fields(&meta.data[FILE_HEADER_SIZE..], META_FREE_CAPACITY)?;
+        }
+        Ok(storage)
+    }
+
+    /// Opens an existing file without ever writing to it. A file that would have to be
+    /// migrated first is refused.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> io::Result<Storage> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let mut storage = Storage::from_file(file)?;
+        let meta = storage.read_page(0)?;
+        storage.version = format::read_version(&meta)?;
+        if storage.version.major != FORMAT_VERSION.major {
+            return Err(invalid_data(format!(
+                "format version {} has to be upgraded before the file is read",
+                storage.version
+            )));
+        }
+        storage.read_meta_fields(&meta.data[FILE_HEADER_SIZE..], META_FREE_CAPACITY)?;
+        Ok(storage)
+    }
+
+    /// A storage over `file` whose meta page is not read yet.
+    fn from_file(file: File) -> io::Result<Storage> {
+        let len = file.metadata()?.len();
+        Ok(Storage {
+            current_file: Mutex::new(file),
+            root: 0,
+            page_count: (len / PAGE_SIZE as u64) as u32,
+            free: vec![],
+            held: vec![],
+            retired: BTreeSet::new(),
+            trunks: vec![],
+            version: FORMAT_VERSION,
+        })
+    }
+
+    /// Reads the root and the free list from the meta page fields after the header,
+    /// `capacity` free pages are listed in the meta page itself.
+    fn read_meta_fields(&mut self, fields: &[u8], capacity: usize) -> io::Result<()> {
+        let mut reader = ByteDecoder::new(fields);
+        self.free.clear();
+        self.held.clear();
+        self.retired.clear();
+        self.trunks.clear();
+        self.root = reader.read_u32()?;
+        let count = reader.read_u32()? as usize;
+        let mut trunk = reader.read_u32()?;
+        if count > capacity {
+            return Err(invalid_data(format!("bad free list length {count}")));
+        }
+        for _ in 0..count {
+            self.free.push(reader.read_u32()?);
+        }
+        while trunk != 0 {
+            let page = self.read_page(trunk)?;
+            let header = page.header()?;
+            if header.page_type != BTreePageType::FreeList {
+                return Err(invalid_data(format!(
+                    "page {trunk} is not a free list trunk"
+                )));
+            }
+            let mut reader = ByteDecoder::new(&page.data[PAGE_HEADER_SIZE..]);
+            for _ in 0..(header.n_cells as usize).min(TRUNK_CAPACITY) {
+                self.free.push(reader.read_u32()?);
+            }
+            self.held.push(trunk);
+            trunk = header.right_pointer;
+        }
+        Ok(())
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Page number of the B-tree root, 0 while the file holds no tree.
+    pub fn root(&self) -> u32 {
+        self.root
+    }
+
+    /// Changes the root in memory, it reaches the file with the next `write_meta`.
+    pub fn set_root(&mut self, root: u32) {
+        self.root = root;
+    }
+
+    /// Reserves a free page, or one at the end of the file which exists once written.
+    pub fn allocate_page(&mut self) -> u32 {
+        if let Some(page) = self.free.pop() {
+            return page;
+        }
+        self.page_count += 1;
+        self.page_count - 1
+    }
+
+    /// Gives back a page, which is reused once a meta page listing it as free is synced.
+    pub fn free_page(&mut self, number: u32) {
+        self.retired.remove(&number);
+        self.held.push(number);
+    }
+
+    /// Marks a page that left the tree while snapshots may read it, until `free_page`
+    /// gives it back.
+    pub fn retire_page(&mut self, number: u32) {
+        self.retired.insert(number);
+    }
+
+    pub fn page_count(&self) -> u32 {
+        self.page_count
+    }
+
+    /// Every free page, the ones held back included.
+    pub fn free_pages(&self) -> Vec<u32> {
+        let mut pages = self.listed();
+        pages.extend(&self.trunks);
+        pages
+    }
+
+    /// Drops the pages from `page_count` on along with the free list, whatever was in
+    /// use there must have been moved into the free pages below it. The file shrinks
+    /// with the next `write_meta`.
+    pub fn truncate(&mut self, page_count: u32) {
+        self.free.clear();
+        self.held.clear();
+        self.retired.clear();
+        self.trunks.clear();
+        self.page_count = page_count;
+    }
+
+    pub fn read_page(&self, number: u32) -> io::Result<Page> {
+        if number >= self.page_count {
+            return Err(invalid_data(format!(
+                "page {number} is past the end of the file"
+            )));
+        }
+        let mut page = Page::new(number);
+        let mut file = self.current_file.lock().unwrap();
+        file.seek(SeekFrom::Start(number as u64 * PAGE_SIZE as u64))?;
+        file.read_exact(&mut page.data[..])?;
+        if !page.verify() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                CorruptPage { page: number },
+            ));
+        }
+        Ok(page)
+    }
+
+    /// Writes the page sealed with its checksum.
+    pub fn write_page(&self, page: &Page) -> io::Result<()> {
+        let mut sealed = page.clone();
+        sealed.seal();
+        let mut file = self.current_file.lock().unwrap();
+        file.seek(SeekFrom::Start(page.number as u64 * PAGE_SIZE as u64))?;
+        file.write_all(&sealed.data[..])
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.current_file.lock().unwrap().sync_data()
+    }
+
+    /// Writes and syncs the free list trunks, then the meta page, see `write_meta_page`.
+    pub fn write_meta(&mut self) -> io::Result<()> {
+        self.write_free_list()?;
+        self.sync()?;
+        self.write_meta_page()
+    }
+
+    /// Picks and writes the trunk pages holding the part of the free list the meta page
+    /// has no room for. Trunks are only taken from `free` or past the end of the file,
+    /// so the meta page on disk stays intact along with its free list.
+    pub fn write_free_list(&mut self) -> io::Result<()> {
+        // Trunks picked for a meta page that never got written are free pages again.
+        self.free.append(&mut self.trunks);
+        let mut listed = self.free.len() + self.held.len() + self.retired.len();
+        while listed > META_FREE_CAPACITY + self.trunks.len() * TRUNK_CAPACITY {
+            match self.free.pop() {
+                Some(page) => {
+                    self.trunks.push(page);
+                    listed -= 1;
+                }
+                None => {
+                    self.trunks.push(self.page_count);
+                    self.page_count += 1;
+                }
+            }
+        }
+        let listed = self.listed();
+        let mut rest = &listed[listed.len().min(META_FREE_CAPACITY)..];
+        for (idx, number) in self.trunks.iter().enumerate() {
+            let (pages, remaining) = rest.split_at(rest.len().min(TRUNK_CAPACITY));
+            rest = remaining;
+            let mut page = Page::new(*number);
+            let header = PageHeader {
+                page_type: BTreePageType::FreeList,
+                offset: 0,
+                n_cells: pages.len() as u16,
+                cell_offset: PAGE_HEADER_SIZE as u16,
+                right_pointer: self.trunks.get(idx + 1).copied().unwrap_or(0),
+            };
+            page.data[..PAGE_HEADER_SIZE].copy_from_slice(&header.to_bytes()?);
+            let mut writer = ByteEncoder::new(&mut page.data[PAGE_HEADER_SIZE..]);
+            for free in pages {
+                writer.write_u32(*free)?;
+            }
+            self.write_page(&page)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the meta page with the trunks of the last `write_free_list`, sizes the
+    /// file to its page count and syncs it. It publishes the root and the free list, so
+    /// the pages they refer to must be synced already. Once it is on disk the held pages
+    /// are free, and its own trunks are held instead.
+    pub fn write_meta_page(&mut self) -> io::Result<()> {
+        let listed = self.listed();
+        let listed = &listed[..listed.len().min(META_FREE_CAPACITY)];
+        let mut meta = Page::new(0);
+        let header = FileHeader {
+            page_size: PAGE_SIZE as u32,
+            version: self.version,
+        };
+        header.write(&mut meta)?;
+        let mut writer = ByteEncoder::new(&mut meta.data[FILE_HEADER_SIZE..USABLE_SIZE]);
+        writer.write_u32(self.root)?;
+        writer.write_u32(listed.len() as u32)?;
+        writer.write_u32(self.trunks.first().copied().unwrap_or(0))?;
+        for page in listed {
+            writer.write_u32(*page)?;
+        }
+        self.write_page(&meta)?;
+        {
+            let file = self.current_file.lock().unwrap();
+            let len = self.page_count as u64 * PAGE_SIZE as u64;
+            if file.metadata()?.len() != len {
+                file.set_len(len)?;
+            }
+        }
+        self.sync()?;
+        self.free.append(&mut self.held);
+        self.held.append(&mut self.trunks);
+        Ok(())
+    }
+
+    /// Free pages other than trunks, in the order the meta page and its trunks list them.
+    fn listed(&self) -> Vec<u32> {
+        let pages = self.free.iter().chain(&self.held).chain(&self.retired);
+        pages.copied().collect()
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::format::Version;
+use super::paging::{Page, PAGE_SIZE};
+use super::Storage;
+
+/// Fewest pages a pager caches whatever its budget, enough to pin a root to leaf path
+/// while a split writes new pages.
+pub const MIN_CACHED_PAGES: usize = 16;
+
+/// Identifies a file opened with `Pager::open_file`.
+pub type FileId = usize;
+
+type PageId = (FileId, u32);
+
+/// A page kept in memory by the pager. Its frame cannot be evicted while the guard is
+/// alive.
+pub struct PinnedPage(Arc<Page>);
+
+impl Deref for PinnedPage {
+    type Target = Page;
+
+    fn deref(&self) -> &Page {
+        &self.0
+    }
+}
+
+struct Frame {
+    page: Arc<Page>,
+    dirty: bool,
+    last_used: u64,
+}
+
+impl Frame {
+    /// Guards hand out clones of the frame's `Arc`, so a frame is pinned while any exist.
+    fn is_pinned(&self) -> bool {
+        Arc::strong_count(&self.page) > 1
+    }
+}
+
+struct Cache {
+    files: Vec<Storage>,
+    frames: HashMap<PageId, Frame>,
+    /// Frames by the tick they were last used at, least recently used first.
+    lru: BTreeMap<u64, PageId>,
+    tick: u64,
+    capacity: usize,
+}
+
+/// Page cache shared by the B-tree files of a database. It holds at most `capacity`
+/// pages across all files, evicting the least recently used unpinned page and writing
+/// it back first if it is dirty. Only when every cached page is pinned does it go over.
+pub struct Pager {
+    cache: Mutex<Cache>,
+}
+
+impl Pager {
+    /// Creates a pager caching as many pages as fit in `budget` bytes.
+    pub fn new(budget: usize) -> Pager {
+        Pager {
+            cache: Mutex::new(Cache {
+                files: vec![],
+                frames: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                capacity: (budget / PAGE_SIZE).max(MIN_CACHED_PAGES),
+            }),
+        }
+    }
+
+    pub fn open_file<P: AsRef<Path>>(&self, path: P) -> io::Result<FileId> {
+        let storage = Storage::open(path)?;
+        let mut cache = self.cache.lock().unwrap();
+        cache.files.push(storage);
+        Ok(cache.files.len() - 1)
+    }
+
+    pub fn root(&self, file: FileId) -> u32 {
+        self.cache.lock().unwrap().files[file].root()
+    }
+
+    pub fn set_root(&self, file: FileId, root: u32) {
+        self.cache.lock().unwrap().files[file].set_root(root);
+    }
+
+    pub fn allocate_page(&self, file: FileId) -> u32 {
+        self.cache.lock().unwrap().files[file].allocate_page()
+    }
+
+    /// Drops the page from the cache without writing it back and returns it to the file.
+    pub fn free_page(&self, file: FileId, number: u32) {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(frame) = cache.frames.remove(&(file, number)) {
+            cache.lru.remove(&frame.last_used);
+        }
+        cache.files[file].free_page(number);
+    }
+
+    /// Marks a page that left the tree but may still be read, see `Storage::retire_page`.
+    pub fn retire_page(&self, file: FileId, number: u32) {
+        self.cache.lock().unwrap().files[file].retire_page(number);
+    }
+
+    /// Format version of the file as opened, after any migrations.
+    pub fn version(&self, file: FileId) -> Version {
+        self.cache.lock().unwrap().files[file].version()
+    }
+
+    /// Number of pages in the file, including free ones.
+    pub fn page_count(&self, file: FileId) -> u32 {
+        self.cache.lock().unwrap().files[file].page_count()
+    }
+
+    pub fn free_pages(&self, file: FileId) -> Vec<u32> {
+        self.cache.lock().unwrap().files[file].free_pages()
+    }
+
+    /// Cuts the file down to `page_count` pages and empties its free list, whatever was
+    /// in use past that must have been moved into the free pages below it. Frames past
+    /// the end are dropped and the file shrinks on the next `flush`.
+    pub fn truncate(&self, file: FileId, page_count: u32) {
+        let mut cache = self.cache.lock().unwrap();
+        let cut: Vec<PageId> = cache
+            .frames
+            .keys()
+            .filter(|(f, number)| *f == file && *number >= page_count)
+            .copied()
+            .collect();
+        for id in cut {
+            let frame = cache.frames.remove(&id).unwrap();
+            cache.lru.remove(&frame.last_used);
+        }
+        cache.files[file].truncate(page_count);
+    }
+
+    /// Returns the page, reading it from its file if it is not cached.
+    pub fn get_page(&self, file: FileId, number: u32) -> io::Result<PinnedPage> {
+        let mut cache = self.cache.lock().unwrap();
+        let id = (file, number);
+        if !cache.frames.contains_key(&id) {
+            let page = cache.files[file].read_page(number)?;
+            cache.insert(id, page, false)?;
+        }
+        cache.touch(id);
+        Ok(PinnedPage(cache.frames[&id].page.clone()))
+    }
+
+    /// Replaces the cached page, it is written to its file on eviction or `flush`.
+    /// Readers holding the previous version keep seeing it.
+    pub fn write_page(&self, file: FileId, page: Page) -> io::Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        let id = (file, page.number);
+        match cache.frames.get_mut(&id) {
+            Some(frame) => {
+                frame.page = Arc::new(page);
+                frame.dirty = true;
+            }
+            None => cache.insert(id, page, true)?,
+        }
+        cache.touch(id);
+        Ok(())
+    }
+
+    /// Writes dirty pages back in file and page order along with the free list trunks and
+    /// syncs them, then writes and syncs the meta page of every file. None of these
+    /// writes touch a page the previous meta page refers to, see `Storage`, so a crash in
+    /// between leaves it intact along with the tree and free list it points to.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut cache = self.cache.lock().unwrap();
+        let mut dirty: Vec<PageId> = cache
+            .frames
+            .iter()
+            .filter(|(_, frame)| frame.dirty)
+            .map(|(id, _)| *id)
+            .collect();
+        dirty.sort_unstable();
+        for id in dirty {
+            cache.files[id.0].write_page(&cache.frames[&id].page)?;
+            cache.frames.get_mut(&id).unwrap().dirty = false;
+        }
+        for storage in &mut cache.files {
+            storage.write_free_list()?;
+            storage.sync()?;
+        }
+        for storage in &mut cache.files {
+            storage.write_meta_page()?;
+        }
+        Ok(())
+    }
+
+    /// Number of pages currently cached.
+    pub fn cached(&self) -> usize {
+        self.cache.lock().unwrap().frames.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cache.lock().unwrap().capacity
+    }
+}
+
+impl Cache {
+    fn insert(&mut self, id: PageId, page: Page, dirty: bool) -> io::Result<()> {
+        while self.frames.len() >= self.capacity {
+            if !self.evict()? {
+                break;
+            }
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, id);
+        let frame = Frame {
+            page: Arc::new(page),
+            dirty,
+            last_used: self.tick,
+        };
+        self.frames.insert(id, frame);
+        Ok(())
+    }
+
+    fn touch(&mut self, id: PageId) {
+        self.tick += 1;
+        let frame = self.frames.get_mut(&id).unwrap();
+        self.lru.remove(&frame.last_used);
+        frame.last_used = self.tick;
+        self.lru.insert(self.tick, id);
+    }
+
+    /// Drops the least recently used unpinned page, false if every page is pinned.
+    fn evict(&mut self) -> io::Result<bool> {
+        let victim = self
+            .lru
+            .iter()
+            .find(|(_, id)| !self.frames[id].is_pinned())
+            .map(|(tick, id)| (*tick, *id));
+        let Some((tick, id)) = victim else {
+            return Ok(false);
+        };
+        if self.frames[&id].dirty {
+            self.files[id.0].write_page(&self.frames[&id].page)?;
+        }
+        self.lru.remove(&tick);
+        self.frames.remove(&id);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::paging::USABLE_SIZE;
+
+    fn page(number: u32, fill: u8) -> Page {
+        let mut page = Page::new(number);
+        page.data.fill(fill);
+        page
+    }
+
+    #[test]
+    fn evicts_least_recently_used_unpinned_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let pager = Pager::new(0);
+        let file = pager.open_file(dir.path().join("pages.db")).unwrap();
+        let capacity = pager.capacity() as u32;
+        for _ in 0..=capacity {
+            let number = pager.allocate_page(file);
+            pager.write_page(file, page(number, number as u8)).unwrap();
+        }
+        assert_eq!(pager.cached(), capacity as usize);
+
+        // Page 1 was evicted and written back, reading it pins it again.
+        let pinned = pager.get_page(file, 1).unwrap();
+        assert_eq!(pinned.data[0], 1);
+        for number in 2..=capacity + 1 {
+            pager.get_page(file, number).unwrap();
+        }
+        let cache = pager.cache.lock().unwrap();
+        assert!(cache.frames.contains_key(&(file, 1)));
+        assert_eq!(cache.frames.len(), capacity as usize);
+    }
+
+    #[test]
+    fn flush_writes_dirty_pages_and_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pages.db");
+        let pager = Pager::new(1024 * 1024);
+        let file = pager.open_file(&path).unwrap();
+        let number = pager.allocate_page(file);
+        pager.write_page(file, page(number, 7)).unwrap();
+        pager.set_root(file, number);
+        pager.flush().unwrap();
+
+        let pager = Pager::new(0);
+        let file = pager.open_file(&path).unwrap();
+        assert_eq!(pager.root(file), number);
+        assert_eq!(
+            pager.get_page(file, number).unwrap().data[USABLE_SIZE - 1],
+            7
+        );
+    }
+
+    #[test]
+    fn free_list_survives_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pages.db");
+        let pager = Pager::new(0);
+        let file = pager.open_file(&path).unwrap();
+        // More free pages than the meta page lists, the rest go to trunk pages.
+        let pages: Vec<u32> = (0..3000).map(|_| pager.allocate_page(file)).collect();
+        for number in &pages {
+            pager.write_page(file, page(*number, 1)).unwrap();
+        }
+        for number in pages.iter().filter(|number| *number % 3 != 0) {
+            pager.free_page(file, *number);
+        }
+        pager.flush().unwrap();
+        let mut freed = pager.free_pages(file);
+        freed.sort_unstable();
+        // Pages given back since the last meta page cannot hold its trunks, those go past
+        // the pages written.
+        let page_count = pager.page_count(file);
+        assert!(page_count > 3001);
+
+        let pager = Pager::new(0);
+        let file = pager.open_file(&path).unwrap();
+        let mut reopened = pager.free_pages(file);
+        reopened.sort_unstable();
+        assert_eq!(reopened, freed);
+        assert_eq!(pager.page_count(file), page_count);
+        // The trunks of the meta page on disk are only reused once a new one is synced.
+        let mut trunks = pager.cache.lock().unwrap().files[file].held.clone();
+        assert!(!trunks.is_empty());
+        let allocated: Vec<u32> = (trunks.len()..freed.len())
+            .map(|_| pager.allocate_page(file))
+            .collect();
+        assert!(allocated
+            .iter()
+            .all(|number| freed.contains(number) && !trunks.contains(number)));
+        assert_eq!(pager.allocate_page(file), page_count);
+        pager.flush().unwrap();
+        let mut reused: Vec<u32> = trunks.iter().map(|_| pager.allocate_page(file)).collect();
+        reused.sort_unstable();
+        trunks.sort_unstable();
+        assert_eq!(reused, trunks);
+    }
+
+    #[test]
+    fn flushes_cut_short_leave_the_last_meta_page_intact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pages.db");
+        let pager = Pager::new(0);
+        let file = pager.open_file(&path).unwrap();
+        let pages: Vec<u32> = (0..3000).map(|_| pager.allocate_page(file)).collect();
+        for number in &pages {
+            pager.write_page(file, page(*number, 1)).unwrap();
+        }
+        for number in pages.iter().filter(|number| *number % 3 != 0) {
+            pager.free_page(file, *number);
+        }
+        pager.flush().unwrap();
+        let mut durable = pager.free_pages(file);
+        durable.sort_unstable();
+
+        // More pages leave the tree, then a flush stops once the trunks are written.
+        let freed: Vec<u32> = pages.iter().filter(|n| *n % 3 == 0).copied().collect();
+        for number in &freed {
+            pager.free_page(file, *number);
+        }
+        {
+            let mut cache = pager.cache.lock().unwrap();
+            cache.files[file].write_free_list().unwrap();
+            cache.files[file].sync().unwrap();
+        }
+        drop(pager);
+
+        // The tree on disk still has the pages given back since, and its free list reads
+        // as it was written.
+        let pager = Pager::new(0);
+        let file = pager.open_file(&path).unwrap();
+        let mut reopened = pager.free_pages(file);
+        reopened.sort_unstable();
+        assert_eq!(reopened, durable);
+        for number in &freed {
+            assert_eq!(pager.get_page(file, *number).unwrap().data[0], 1);
+        }
+    }
+}
@@ -1,25 +1,44 @@
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Read, Result, Write};
-use std::path::Path;
+use std::io::{BufReader, Result};
 
 use crate::byte_encoder::{ByteDecoder, ByteEncoder};
 
-use super::b_tree::{BTreePageType, Node};
+use super::b_tree::BTreePageType;
+use super::invalid_data;
 
+pub const PAGE_SIZE: usize = 4096;
+
+/// Bytes at the end of every page holding the CRC-32 of the rest of it.
+pub const PAGE_CHECKSUM_SIZE: usize = 4;
+
+/// Bytes of a page available for its content, everything before the checksum.
+pub const USABLE_SIZE: usize = PAGE_SIZE - PAGE_CHECKSUM_SIZE;
+
+/// Bytes taken by `PageHeader` at the start of every B-tree page.
+pub const PAGE_HEADER_SIZE: usize = 1 + 2 + 2 + 2 + 4;
+
+/// Bytes of a spilled value held by each overflow page.
+pub const OVERFLOW_CAPACITY: usize = USABLE_SIZE - PAGE_HEADER_SIZE;
+
+/// A B-tree page is a slotted page: the header, then an array of `u16` cell pointers
+/// in key order growing forward, and the cells themselves packed from the checksum at
+/// the end of the page backwards. `cell_offset` is where the cell content area starts.
 #[repr(C, packed)]
-struct PageHeader {
-    page_type: BTreePageType,
-    offset: u16,
-    n_cells: u16,
-    cell_offset: u16,
-    right_pointer: u32,
+pub struct PageHeader {
+    pub page_type: BTreePageType,
+    /// Offset of the first free block, pages are always written compacted so this is 0.
+    pub offset: u16,
+    pub n_cells: u16,
+    pub cell_offset: u16,
+    /// Right-most child of an interior page, or next page of an overflow chain or of the
+    /// free list trunks. Leaves leave it 0.
+    pub right_pointer: u32,
 }
 
 impl PageHeader {
     fn from_bytes(bytes: &[u8]) -> Result<PageHeader> {
         let mut reader = ByteDecoder::new(BufReader::new(bytes));
         Ok(PageHeader {
-            page_type: BTreePageType::from(reader.read_u8()?),
+            page_type: BTreePageType::try_from(reader.read_u8()?)?,
             offset: reader.read_u16()?,
             n_cells: reader.read_u16()?,
             cell_offset: reader.read_u16()?,
@@ -27,8 +46,9 @@ impl PageHeader {
         })
     }
 
-    fn to_bytes(&self) -> Result<Vec<u8>> {
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
         let mut writer = ByteEncoder::new(vec![]);
+        writer.write_u8(self.page_type.into())?;
         writer.write_u16(self.offset)?;
         writer.write_u16(self.n_cells)?;
         writer.write_u16(self.cell_offset)?;
@@ -37,23 +57,160 @@ impl PageHeader {
     }
 }
 
-const PAGE_SIZE: usize = 4096;
-
+/// A raw page image together with its position in the file.
+#[derive(Clone)]
 pub struct Page {
-    data: [u8; PAGE_SIZE],
-    btree_keys: Vec<u32>,
-    checksum: Vec<u8>,
-    header: PageHeader,
+    pub number: u32,
+    pub data: Box<[u8; PAGE_SIZE]>,
 }
 
 impl Page {
-    // fn new() -> Self {
-    //     Page {
-    //         data: [0; PAGE_SIZE],
-    //         btree_keys: Vec::new(),
-    //         checksum: vec![],
-    //     }
-    // }
-
-    pub fn write_to_file(&self) {}
+    pub fn new(number: u32) -> Self {
+        Page {
+            number,
+            data: Box::new([0; PAGE_SIZE]),
+        }
+    }
+
+    /// Lays out `cells` in a fresh slotted page.
+    pub fn from_cells(
+        number: u32,
+        page_type: BTreePageType,
+        right_pointer: u32,
+        cells: &[Vec<u8>],
+    ) -> Result<Page> {
+        let mut page = Page::new(number);
+        let mut cell_offset = USABLE_SIZE;
+        let mut pointers = ByteEncoder::new(Vec::with_capacity(cells.len() * 2));
+        for cell in cells {
+            let pointer_end = PAGE_HEADER_SIZE + (pointers.inner.len() + 2);
+            if cell_offset < pointer_end + cell.len() {
+                return Err(invalid_data(format!("cells overflow page {number}")));
+            }
+            cell_offset -= cell.len();
+            page.data[cell_offset..cell_offset + cell.len()].copy_from_slice(cell);
+            pointers.write_u16(cell_offset as u16)?;
+        }
+
+        let header = PageHeader {
+            page_type,
+            offset: 0,
+            n_cells: cells.len() as u16,
+            cell_offset: cell_offset as u16,
+            right_pointer,
+        };
+        page.data[..PAGE_HEADER_SIZE].copy_from_slice(&header.to_bytes()?);
+        page.data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + pointers.inner.len()]
+            .copy_from_slice(&pointers.inner);
+        Ok(page)
+    }
+
+    /// Lays out a chunk of a spilled value in an overflow page. Overflow pages have no
+    /// cells, the chunk fills the page after the header and `next` links the chain.
+    pub fn overflow(number: u32, next: u32, chunk: &[u8]) -> Result<Page> {
+        if chunk.len() > OVERFLOW_CAPACITY {
+            return Err(invalid_data(format!("chunk overflows page {number}")));
+        }
+        let mut page = Page::new(number);
+        let header = PageHeader {
+            page_type: BTreePageType::Overflow,
+            offset: 0,
+            n_cells: 0,
+            cell_offset: PAGE_HEADER_SIZE as u16,
+            right_pointer: next,
+        };
+        page.data[..PAGE_HEADER_SIZE].copy_from_slice(&header.to_bytes()?);
+        page.data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + chunk.len()].copy_from_slice(chunk);
+        Ok(page)
+    }
+
+    /// Content of an overflow page, only the start of it is used on the last page.
+    pub fn payload(&self) -> &[u8] {
+        &self.data[PAGE_HEADER_SIZE..USABLE_SIZE]
+    }
+
+    fn checksum(&self) -> u32 {
+        crc32fast::hash(&self.data[..USABLE_SIZE])
+    }
+
+    /// Stores the checksum of the page content in its last bytes.
+    pub fn seal(&mut self) {
+        let checksum = self.checksum().to_le_bytes();
+        self.data[USABLE_SIZE..].copy_from_slice(&checksum);
+    }
+
+    /// Whether the page content matches the checksum it was sealed with.
+    pub fn verify(&self) -> bool {
+        self.data[USABLE_SIZE..] == self.checksum().to_le_bytes()
+    }
+
+    pub fn header(&self) -> Result<PageHeader> {
+        PageHeader::from_bytes(&self.data[..PAGE_HEADER_SIZE])
+    }
+
+    /// The cells of the page in pointer order. Cells are packed back to back, so each
+    /// one ends where the closest cell after it in the page begins.
+    pub fn cells(&self) -> Result<Vec<&[u8]>> {
+        let header = self.header()?;
+        let n_cells = header.n_cells as usize;
+        let pointer_end = PAGE_HEADER_SIZE + n_cells * 2;
+        if pointer_end > USABLE_SIZE {
+            return Err(invalid_data(format!(
+                "bad cell count on page {}",
+                self.number
+            )));
+        }
+        let mut reader = ByteDecoder::new(&self.data[PAGE_HEADER_SIZE..pointer_end]);
+        let mut pointers = Vec::with_capacity(n_cells);
+        for _ in 0..n_cells {
+            let pointer = reader.read_u16()? as usize;
+            if pointer < pointer_end || pointer >= USABLE_SIZE {
+                return Err(invalid_data(format!(
+                    "bad cell pointer on page {}",
+                    self.number
+                )));
+            }
+            pointers.push(pointer);
+        }
+
+        let mut sorted = pointers.clone();
+        sorted.sort_unstable();
+        Ok(pointers
+            .iter()
+            .map(|start| {
+                let end = match sorted.binary_search(start) {
+                    Ok(idx) if idx + 1 < sorted.len() => sorted[idx + 1],
+                    _ => USABLE_SIZE,
+                };
+                &self.data[*start..end]
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_round_trip() {
+        let cells = vec![b"first".to_vec(), b"2".to_vec(), b"third cell".to_vec()];
+        let page = Page::from_cells(3, BTreePageType::InteriorTable, 42, &cells).unwrap();
+
+        let header = page.header().unwrap();
+        assert!(matches!(header.page_type, BTreePageType::InteriorTable));
+        assert_eq!({ header.n_cells }, 3);
+        assert_eq!({ header.right_pointer }, 42);
+        assert_eq!({ header.cell_offset } as usize, USABLE_SIZE - 16);
+        assert_eq!(
+            page.cells().unwrap(),
+            vec![&b"first"[..], &b"2"[..], &b"third cell"[..]]
+        );
+    }
+
+    #[test]
+    fn rejects_cells_that_do_not_fit() {
+        let cells = vec![vec![0; PAGE_SIZE / 2], vec![0; PAGE_SIZE / 2]];
+        assert!(Page::from_cells(1, BTreePageType::LeafTable, 0, &cells).is_err());
+    }
 }
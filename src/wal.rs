@@ -1,40 +1,734 @@
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use crate::byte_encoder::{ByteDecoder, ByteEncoder};
+
+/// Every record is framed as `crc32 | length | sequence | kind | payload`. The checksum
+/// covers everything after itself, so a record torn by a power cut fails verification.
+const RECORD_HEADER_SIZE: u64 = 4 + 4 + 8 + 1;
+
+/// Upper bound on a single payload, anything larger is treated as a corrupt length.
+const MAX_RECORD_SIZE: u32 = 64 * 1024 * 1024;
+
+const SEGMENT_EXTENSION: &str = "wal";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Data,
+    /// Marks every record up to the sequence in its payload as durable in storage.
+    Checkpoint,
+}
+
+impl RecordKind {
+    fn from_u8(value: u8) -> Option<RecordKind> {
+        match value {
+            0 => Some(RecordKind::Data),
+            1 => Some(RecordKind::Checkpoint),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            RecordKind::Data => 0,
+            RecordKind::Checkpoint => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub sequence: u64,
+    pub kind: RecordKind,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// The sequence a checkpoint record covers, `None` for data records.
+    pub fn checkpoint(&self) -> Option<u64> {
+        match self.kind {
+            RecordKind::Checkpoint => {
+                Some(u64::from_le_bytes(self.data.get(..8)?.try_into().ok()?))
+            }
+            RecordKind::Data => None,
+        }
+    }
+}
+
+/// Why a reader stopped before the end of the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tail {
+    /// A segment ends in the middle of a record.
+    Truncated,
+    /// A record failed its checksum, has an impossible length or is out of sequence.
+    Corrupt,
+}
+
+/// When a write is made durable with `fsync`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync before every write is acknowledged. Writers that arrive while a sync is in
+    /// flight share the next one.
+    #[default]
+    Always,
+    /// Acknowledge once a shared sync ran, at most `window` after the oldest unsynced
+    /// record or as soon as `max_bytes` are waiting, whichever comes first.
+    Group { window: Duration, max_bytes: u64 },
+    /// Acknowledge right away and sync at most `interval` later, so a crash can lose up
+    /// to one interval of acknowledged writes.
+    Interval(Duration),
+}
+
+/// An append-only log split into numbered segment files inside one directory. Each
+/// segment is named after the sequence number of its first record.
 pub struct WriteAheadLog {
+    dir: PathBuf,
+    segment_size: u64,
+    /// First sequence number of every segment on disk, the last one is being written.
+    segments: Vec<u64>,
     writer: BufWriter<File>,
+    segment_len: u64,
+    next_sequence: u64,
+    last_checkpoint: u64,
+    tail: Option<Tail>,
+    sync: SyncHandle,
+    syncer: Option<JoinHandle<()>>,
+}
+
+/// Shared with writers waiting for durability outside the lock guarding the log, and
+/// with the background thread that issues the syncs.
+#[derive(Clone)]
+pub struct SyncHandle {
+    shared: Arc<SyncShared>,
+}
+
+struct SyncShared {
+    state: Mutex<SyncState>,
+    /// Wakes the syncer when a deadline moves closer and writers when a sync finished.
+    changed: Condvar,
+}
+
+struct SyncState {
+    /// Handle on the segment being written, records in older segments are already synced.
+    file: Arc<File>,
+    written: u64,
+    synced: u64,
+    pending_bytes: u64,
+    oldest_unsynced: Option<Instant>,
+    deadline: Option<Instant>,
+    syncs: u64,
+    error: Option<io::ErrorKind>,
+    shutdown: bool,
 }
 
 impl WriteAheadLog {
-    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<WriteAheadLog> {
-        let file = OpenOptions::new().create(true).append(true).open(path)?;
-        let writer = BufWriter::new(file);
-        Ok(WriteAheadLog { writer })
+    /// Opens or creates the log in `dir`, rolling over to a new segment once the current
+    /// one reaches `segment_size` bytes. A torn or corrupt tail left by a crash is cut
+    /// off, together with any segment after it, so new records follow the last valid one.
+    pub fn open<P: AsRef<Path>>(dir: P, segment_size: u64) -> io::Result<WriteAheadLog> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+
+        let mut next_sequence = 1;
+        let mut last_checkpoint = 0;
+        let mut reader = WriteAheadLogReader::open(&dir)?;
+        for record in reader.by_ref() {
+            let record = record?;
+            next_sequence = record.sequence + 1;
+            last_checkpoint = record.checkpoint().unwrap_or(last_checkpoint);
+        }
+        let tail = reader.tail();
+
+        let mut segments: Vec<u64> = list_segments(&dir)?.into_iter().map(|s| s.0).collect();
+        if let Some((stopped_in, valid_len)) = reader.stopped_at() {
+            for first in segments.iter().filter(|first| **first > stopped_in) {
+                fs::remove_file(segment_path(&dir, *first))?;
+            }
+            segments.retain(|first| *first <= stopped_in);
+            let file = OpenOptions::new()
+                .write(true)
+                .open(segment_path(&dir, stopped_in))?;
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        if segments.is_empty() {
+            segments.push(next_sequence);
+        }
+
+        let current = *segments.last().unwrap();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&dir, current))?;
+        let segment_len = file.metadata()?.len();
+        let sync = SyncHandle {
+            shared: Arc::new(SyncShared {
+                state: Mutex::new(SyncState {
+                    file: Arc::new(file.try_clone()?),
+                    written: next_sequence - 1,
+                    synced: next_sequence - 1,
+                    pending_bytes: 0,
+                    oldest_unsynced: None,
+                    deadline: None,
+                    syncs: 0,
+                    error: None,
+                    shutdown: false,
+                }),
+                changed: Condvar::new(),
+            }),
+        };
+        let syncer = {
+            let sync = sync.clone();
+            thread::Builder::new()
+                .name("wal-sync".to_owned())
+                .spawn(move || sync.run())?
+        };
+        Ok(WriteAheadLog {
+            dir,
+            segment_size,
+            segments,
+            writer: BufWriter::new(file),
+            segment_len,
+            next_sequence,
+            last_checkpoint,
+            tail,
+            sync,
+            syncer: Some(syncer),
+        })
+    }
+
+    /// Appends one data record and returns its sequence number. Payloads above
+    /// `MAX_RECORD_SIZE` are refused with `InvalidInput` before anything is written.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<u64> {
+        self.append(RecordKind::Data, data)
+    }
+
+    /// Handle for waiting until a written sequence is durable without holding on to
+    /// the log itself.
+    pub fn sync_handle(&self) -> SyncHandle {
+        self.sync.clone()
+    }
+
+    /// Records that everything up to and including `sequence` is durable in storage, then
+    /// deletes the segments that only hold records covered by the checkpoint.
+    pub fn checkpoint(&mut self, sequence: u64) -> io::Result<()> {
+        self.append(RecordKind::Checkpoint, &sequence.to_le_bytes())?;
+        self.sync_now()?;
+        self.last_checkpoint = self.last_checkpoint.max(sequence);
+
+        // A segment is obsolete when the segment after it starts at or before the
+        // first record the checkpoint does not cover. The current segment always stays.
+        let mut obsolete = 0;
+        while obsolete + 1 < self.segments.len()
+            && self.segments[obsolete + 1] <= self.last_checkpoint + 1
+        {
+            fs::remove_file(segment_path(&self.dir, self.segments[obsolete]))?;
+            obsolete += 1;
+        }
+        self.segments.drain(..obsolete);
+        Ok(())
+    }
+
+    /// Highest sequence covered by a checkpoint, 0 when none was written.
+    pub fn last_checkpoint(&self) -> u64 {
+        self.last_checkpoint
+    }
+
+    /// Sequence number of the most recently written record, 0 for an empty log.
+    pub fn last_sequence(&self) -> u64 {
+        self.next_sequence - 1
+    }
+
+    /// Set when opening the log found, and cut off, a torn or corrupt tail.
+    pub fn tail(&self) -> Option<Tail> {
+        self.tail
+    }
+
+    /// Number of segment files currently on disk.
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    fn append(&mut self, kind: RecordKind, data: &[u8]) -> io::Result<u64> {
+        // A reader would take a longer record for a corrupt tail and drop it.
+        if data.len() > MAX_RECORD_SIZE as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "record of {} bytes exceeds the limit of {MAX_RECORD_SIZE} bytes",
+                    data.len()
+                ),
+            ));
+        }
+        let record_len = RECORD_HEADER_SIZE + data.len() as u64;
+        if self.segment_len > 0 && self.segment_len + record_len > self.segment_size {
+            self.rotate()?;
+        }
+
+        let sequence = self.next_sequence;
+        let mut body = ByteEncoder::new(Vec::with_capacity(record_len as usize));
+        body.write_u32(data.len() as u32)?;
+        body.write_u64(sequence)?;
+        body.write_u8(kind.to_u8())?;
+        body.write_bytes(data)?;
+
+        let mut record = ByteEncoder::new(&mut self.writer);
+        record.write_u32(crc32fast::hash(&body.inner))?;
+        record.write_bytes(&body.inner)?;
+        self.writer.flush()?;
+        self.segment_len += record_len;
+        self.next_sequence += 1;
+
+        let mut state = self.sync.shared.state.lock().unwrap();
+        state.written = sequence;
+        state.pending_bytes += record_len;
+        state.oldest_unsynced.get_or_insert_with(Instant::now);
+        Ok(sequence)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.sync_now()?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&self.dir, self.next_sequence))?;
+        self.sync.shared.state.lock().unwrap().file = Arc::new(file.try_clone()?);
+        self.writer = BufWriter::new(file);
+        self.segments.push(self.next_sequence);
+        self.segment_len = 0;
+        Ok(())
+    }
+
+    /// Syncs the current segment on the calling thread, outside of any policy.
+    fn sync_now(&mut self) -> io::Result<()> {
+        self.writer.get_ref().sync_data()?;
+        let mut state = self.sync.shared.state.lock().unwrap();
+        state.synced = state.written;
+        state.pending_bytes = 0;
+        state.oldest_unsynced = None;
+        state.syncs += 1;
+        self.sync.shared.changed.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for WriteAheadLog {
+    fn drop(&mut self) {
+        self.sync.shared.state.lock().unwrap().shutdown = true;
+        self.sync.shared.changed.notify_all();
+        if let Some(syncer) = self.syncer.take() {
+            let _ = syncer.join();
+        }
+    }
+}
+
+impl SyncHandle {
+    /// Returns once `sequence` is as durable as `policy` demands: synced for `Always` and
+    /// `Group`, scheduled for a later sync for `Interval`.
+    pub fn wait(&self, sequence: u64, policy: SyncPolicy) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        let now = Instant::now();
+        let oldest = state.oldest_unsynced.unwrap_or(now);
+        let deadline = match policy {
+            SyncPolicy::Always => now,
+            SyncPolicy::Group { max_bytes, .. } if state.pending_bytes >= max_bytes => now,
+            SyncPolicy::Group { window, .. } => oldest + window,
+            SyncPolicy::Interval(interval) => oldest + interval,
+        };
+        if state.synced < sequence && state.deadline.is_none_or(|d| deadline < d) {
+            state.deadline = Some(deadline);
+            self.shared.changed.notify_all();
+        }
+        if let SyncPolicy::Interval(_) = policy {
+            return Ok(());
+        }
+
+        loop {
+            if let Some(kind) = state.error {
+                return Err(io::Error::new(kind, "syncing the WAL failed"));
+            }
+            if state.synced >= sequence {
+                return Ok(());
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Highest sequence known to be on stable storage.
+    pub fn synced(&self) -> u64 {
+        self.shared.state.lock().unwrap().synced
     }
 
-    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
-        self.writer.write_all(data)?;
-        self.writer.flush()
+    /// Number of syncs issued since the log was opened.
+    pub fn syncs(&self) -> u64 {
+        self.shared.state.lock().unwrap().syncs
+    }
+
+    /// Body of the background thread: sleeps until the earliest requested deadline and
+    /// then syncs everything written so far in one go. A failed sync is sticky, the
+    /// state of the file after an `fsync` error is unknown.
+    fn run(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.shutdown || state.error.is_some() {
+                return;
+            }
+            let now = Instant::now();
+            match state.deadline {
+                Some(deadline) if deadline <= now => {
+                    state.deadline = None;
+                    if state.synced >= state.written {
+                        continue;
+                    }
+                    let target = state.written;
+                    let file = state.file.clone();
+                    state.pending_bytes = 0;
+                    state.oldest_unsynced = None;
+                    drop(state);
+
+                    let result = file.sync_data();
+                    state = self.shared.state.lock().unwrap();
+                    match result {
+                        Ok(()) => state.synced = state.synced.max(target),
+                        Err(err) => state.error = Some(err.kind()),
+                    }
+                    state.syncs += 1;
+                    self.shared.changed.notify_all();
+                }
+                Some(deadline) => {
+                    state = self
+                        .shared
+                        .changed
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0;
+                }
+                None => state = self.shared.changed.wait(state).unwrap(),
+            }
+        }
     }
 }
 
+/// Iterates the records of every segment in order, stopping cleanly at the first
+/// truncated or corrupt record. I/O errors other than an early end of file are
+/// yielded as `Err`.
 pub struct WriteAheadLogReader {
-    reader: BufReader<File>,
+    segments: VecDeque<(u64, PathBuf)>,
+    current: Option<SegmentReader>,
+    last_sequence: Option<u64>,
+    tail: Option<Tail>,
+    done: bool,
+}
+
+struct SegmentReader {
+    first_sequence: u64,
+    reader: ByteDecoder<BufReader<File>>,
+    file_len: u64,
+    valid_len: u64,
 }
 
 impl WriteAheadLogReader {
-    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<WriteAheadLogReader> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        Ok(WriteAheadLogReader { reader })
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<WriteAheadLogReader> {
+        Ok(WriteAheadLogReader {
+            segments: list_segments(dir.as_ref())?.into(),
+            current: None,
+            last_sequence: None,
+            tail: None,
+            done: false,
+        })
+    }
+
+    /// Set once iteration stopped on a damaged record rather than the end of the log.
+    pub fn tail(&self) -> Option<Tail> {
+        self.tail
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.reader.read(buf)
+    /// The segment iteration stopped in and the byte length of its valid records, only
+    /// set when a damaged record was found.
+    fn stopped_at(&self) -> Option<(u64, u64)> {
+        self.tail?;
+        let current = self.current.as_ref()?;
+        Some((current.first_sequence, current.valid_len))
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Record>> {
+        // Records are read back to back, so the next one starts at `valid_len`.
+        while self
+            .current
+            .as_ref()
+            .is_none_or(|current| current.valid_len == current.file_len)
+        {
+            let Some((first_sequence, path)) = self.segments.pop_front() else {
+                return Ok(None);
+            };
+            let file = File::open(path)?;
+            let file_len = file.metadata()?.len();
+            self.current = Some(SegmentReader {
+                first_sequence,
+                reader: ByteDecoder::new(BufReader::new(file)),
+                file_len,
+                valid_len: 0,
+            });
+        }
+        let current = self.current.as_mut().unwrap();
+
+        let checksum = current.reader.read_u32()?;
+        let len = current.reader.read_u32()?;
+        if len > MAX_RECORD_SIZE {
+            self.tail = Some(Tail::Corrupt);
+            return Ok(None);
+        }
+        let sequence = current.reader.read_u64()?;
+        let kind = current.reader.read_u8()?;
+        let data = current.reader.read_bytes(len as usize)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&len.to_le_bytes());
+        hasher.update(&sequence.to_le_bytes());
+        hasher.update(&[kind]);
+        hasher.update(&data);
+        let in_sequence = self.last_sequence.is_none_or(|last| sequence == last + 1);
+        let kind = match RecordKind::from_u8(kind) {
+            Some(kind) if hasher.finalize() == checksum && in_sequence => kind,
+            _ => {
+                self.tail = Some(Tail::Corrupt);
+                return Ok(None);
+            }
+        };
+
+        current.valid_len += RECORD_HEADER_SIZE + len as u64;
+        self.last_sequence = Some(sequence);
+        Ok(Some(Record {
+            sequence,
+            kind,
+            data,
+        }))
+    }
+}
+
+impl Iterator for WriteAheadLogReader {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.read_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                self.done = true;
+                self.tail = Some(Tail::Truncated);
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+fn segment_path(dir: &Path, first_sequence: u64) -> PathBuf {
+    dir.join(format!("{first_sequence:020}.{SEGMENT_EXTENSION}"))
+}
+
+/// Segments in `dir` ordered by the sequence number of their first record.
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = vec![];
+    if !dir.exists() {
+        return Ok(segments);
     }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        let first = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse().ok());
+        if let Some(first) = first {
+            segments.push((first, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEGMENT_SIZE: u64 = 1024 * 1024;
+
+    fn read_all(dir: &Path) -> (Vec<Record>, Option<Tail>) {
+        let mut reader = WriteAheadLogReader::open(dir).unwrap();
+        let records = reader.by_ref().collect::<io::Result<Vec<_>>>().unwrap();
+        (records, reader.tail())
+    }
+
+    fn last_segment(dir: &Path) -> PathBuf {
+        list_segments(dir).unwrap().pop().unwrap().1
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WriteAheadLog::open(dir.path(), SEGMENT_SIZE).unwrap();
+        assert_eq!(wal.write(b"first").unwrap(), 1);
+        assert_eq!(wal.write(b"").unwrap(), 2);
+        assert_eq!(wal.write(b"third").unwrap(), 3);
+
+        let (records, tail) = read_all(dir.path());
+        assert_eq!(tail, None);
+        let records: Vec<_> = records.into_iter().map(|r| (r.sequence, r.data)).collect();
+        assert_eq!(
+            records,
+            vec![(1, b"first".to_vec()), (2, vec![]), (3, b"third".to_vec())]
+        );
+    }
+
+    #[test]
+    fn rejects_records_over_the_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WriteAheadLog::open(dir.path(), SEGMENT_SIZE).unwrap();
+        let oversized = vec![0; MAX_RECORD_SIZE as usize + 1];
+        let err = wal.write(&oversized).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(wal.write(b"after").unwrap(), 1);
+        drop(wal);
+
+        let (records, tail) = read_all(dir.path());
+        assert_eq!(tail, None);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].data, b"after".to_vec());
+    }
+
+    #[test]
+    fn stops_at_torn_and_corrupt_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WriteAheadLog::open(dir.path(), SEGMENT_SIZE).unwrap();
+        wal.write(b"first").unwrap();
+        wal.write(b"second").unwrap();
+        drop(wal);
+        let segment = last_segment(dir.path());
+        let full_len = fs::metadata(&segment).unwrap().len();
+
+        // Power cut in the middle of the second record.
+        let file = OpenOptions::new().write(true).open(&segment).unwrap();
+        file.set_len(full_len - 3).unwrap();
+        let (records, tail) = read_all(dir.path());
+        assert_eq!(records.len(), 1);
+        assert_eq!(tail, Some(Tail::Truncated));
+
+        // Reopening cuts the torn record and continues the sequence.
+        let mut wal = WriteAheadLog::open(dir.path(), SEGMENT_SIZE).unwrap();
+        assert_eq!(wal.tail(), Some(Tail::Truncated));
+        assert_eq!(wal.write(b"again").unwrap(), 2);
+        drop(wal);
+        let (records, tail) = read_all(dir.path());
+        assert_eq!(records[1].data, b"again".to_vec());
+        assert_eq!(tail, None);
+
+        // Flip a payload byte of the last record.
+        let mut bytes = fs::read(&segment).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&segment, bytes).unwrap();
+        let (records, tail) = read_all(dir.path());
+        assert_eq!(records.len(), 1);
+        assert_eq!(tail, Some(Tail::Corrupt));
+    }
+
+    #[test]
+    fn concurrent_writers_share_syncs() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = Arc::new(Mutex::new(
+            WriteAheadLog::open(dir.path(), SEGMENT_SIZE).unwrap(),
+        ));
+        let policy = SyncPolicy::Group {
+            window: Duration::from_millis(20),
+            max_bytes: u64::MAX,
+        };
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let wal = wal.clone();
+                thread::spawn(move || {
+                    for _ in 0..5 {
+                        let (sequence, sync) = {
+                            let mut wal = wal.lock().unwrap();
+                            (wal.write(b"point").unwrap(), wal.sync_handle())
+                        };
+                        sync.wait(sequence, policy).unwrap();
+                        assert!(sync.synced() >= sequence);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let sync = wal.lock().unwrap().sync_handle();
+        assert!(sync.syncs() < 40, "{} syncs for 40 writes", sync.syncs());
+    }
+
+    #[test]
+    fn interval_policy_acknowledges_before_syncing() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut wal = WriteAheadLog::open(dir.path(), SEGMENT_SIZE).unwrap();
+        let sync = wal.sync_handle();
+
+        let sequence = wal.write(b"always").unwrap();
+        sync.wait(sequence, SyncPolicy::Always).unwrap();
+        assert_eq!(sync.synced(), sequence);
+
+        let sequence = wal.write(b"later").unwrap();
+        sync.wait(sequence, SyncPolicy::Interval(Duration::from_millis(10)))
+            .unwrap();
+        let started = Instant::now();
+        while sync.synced() < sequence {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn rotates_segments_and_truncates_on_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        // Room for two 10 byte records per segment.
+        let segment_size = 2 * (RECORD_HEADER_SIZE + 10);
+        let mut wal = WriteAheadLog::open(dir.path(), segment_size).unwrap();
+        for _ in 0..5 {
+            wal.write(&[7; 10]).unwrap();
+        }
+        let firsts: Vec<_> = list_segments(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|s| s.0)
+            .collect();
+        assert_eq!(firsts, vec![1, 3, 5]);
+
+        // Records 1 to 3 are durable, so only the first segment can go.
+        wal.checkpoint(3).unwrap();
+        assert_eq!(wal.segment_count(), 2);
+        drop(wal);
 
-    pub fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
-        self.reader.seek(pos)
+        let wal = WriteAheadLog::open(dir.path(), segment_size).unwrap();
+        assert_eq!(wal.last_checkpoint(), 3);
+        assert_eq!(wal.last_sequence(), 6);
+        let (records, tail) = read_all(dir.path());
+        assert_eq!(tail, None);
+        assert_eq!(records.first().unwrap().sequence, 3);
+        assert_eq!(records.last().unwrap().checkpoint(), Some(3));
     }
 }